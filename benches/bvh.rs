@@ -0,0 +1,71 @@
+use std::sync::Arc;
+use bevy_math::{vec3, vec4};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rand::{Rng, SeedableRng};
+use rand::rngs::SmallRng;
+use rtx_mogu::bvh::BvhNode;
+use rtx_mogu::material::Lambertian;
+use rtx_mogu::aarect::Plane;
+use rtx_mogu::obj::{Hittable, HittableList, Sphere};
+use rtx_mogu::types::Ray;
+use rtx_mogu::util::random_unit_vector;
+
+const SPHERES: usize = 100_000;
+
+//Small spheres scattered through a cube, like the mogu scenes but bigger
+fn spheres(rng: &mut SmallRng) -> Vec<Arc<dyn Hittable + Send + Sync>> {
+    (0..SPHERES)
+        .map(|_| {
+            let center = vec3(rng.gen_range(-100.0..100.0), rng.gen_range(-100.0..100.0), rng.gen_range(-100.0..100.0));
+            Arc::new(Sphere::new(center, rng.gen_range(0.1..0.5), Lambertian::color(vec4(0.5, 0.5, 0.5, 1.0)))) as _
+        })
+        .collect()
+}
+
+fn bvh(c: &mut Criterion) {
+    let mut rng = SmallRng::seed_from_u64(1);
+    let objects = spheres(&mut rng);
+    //rays from the center in every direction, most of them cross the whole cube
+    let rays: Vec<Ray> = (0..1000).map(|_| Ray::new(vec3(0.0, 0.0, 0.0), random_unit_vector(&mut rng), 0.0)).collect();
+
+    let mut group = c.benchmark_group("bvh_100k_spheres");
+    group.sample_size(10);
+    group.bench_function("build", |b| b.iter(|| BvhNode::new(black_box(&objects))));
+    group.bench_function("build_median", |b| b.iter(|| BvhNode::new_median(black_box(&objects))));
+
+    let tree = BvhNode::new(&objects);
+    let median = BvhNode::new_median(&objects);
+    for (name, tree) in [("trace_1000_rays", &tree), ("trace_1000_rays_median", &median)] {
+        group.bench_function(name, |b| {
+            b.iter(|| rays.iter().filter(|r| tree.hit(r, 0.001, f32::INFINITY, &mut rng).is_some()).count())
+        });
+    }
+    group.finish();
+}
+
+//A flat top level list like the scenes in main, but with 1000 objects and a floor plane
+fn flat_list(c: &mut Criterion) {
+    let mut rng = SmallRng::seed_from_u64(2);
+    let list = || {
+        let mut rng = SmallRng::seed_from_u64(3);
+        let mut list = HittableList::new();
+        for _ in 0..1000 {
+            let center = vec3(rng.gen_range(-50.0..50.0), rng.gen_range(0.0..100.0), rng.gen_range(-50.0..50.0));
+            list.add(Sphere::new(center, rng.gen_range(0.5..1.5), Lambertian::color(vec4(0.5, 0.5, 0.5, 1.0))));
+        }
+        list.add(Plane::new(vec3(0.0, -1.0, 0.0), vec3(0.0, 1.0, 0.0), Lambertian::color(vec4(0.5, 0.5, 0.5, 1.0))));
+        list
+    };
+    let rays: Vec<Ray> = (0..1000).map(|_| Ray::new(vec3(0.0, 50.0, 0.0), random_unit_vector(&mut rng), 0.0)).collect();
+
+    let mut group = c.benchmark_group("flat_list_1000_objects");
+    for (name, list) in [("trace_1000_rays_linear", list()), ("trace_1000_rays_accelerated", list().accelerate())] {
+        group.bench_function(name, |b| {
+            b.iter(|| rays.iter().filter(|r| list.hit(**r, 0.001, f32::INFINITY, &mut rng).is_some()).count())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bvh, flat_list);
+criterion_main!(benches);
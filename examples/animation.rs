@@ -0,0 +1,39 @@
+use std::error::Error;
+use bevy_math::{vec3, vec4};
+use rtx_mogu::background::Background;
+use rtx_mogu::camera::Camera;
+use rtx_mogu::material::{DiffuseLight, Lambertian, Metal};
+use rtx_mogu::obj::{Cuboid, HittableList, RotateY, Sphere};
+use rtx_mogu::render::{render_animation, RenderConfig};
+use rtx_mogu::texture::Checker;
+
+//Renders a metal box spinning one full turn on a checkered floor to frames/frame_NNNN.png.
+//Stitch them into a video with e.g. `ffmpeg -framerate 30 -i frames/frame_%04d.png spin.mp4`
+fn main() -> Result<(), Box<dyn Error>> {
+    let config = RenderConfig {
+        width: 320,
+        height: 180,
+        samples_per_pixel: 32,
+        max_depth: 16,
+        seed: Some(1),
+        ..RenderConfig::default()
+    };
+    let camera = Camera::with_autofocus(vec3(0.0, 2.0, 6.0), vec3(0.0, 0.5, 0.0), vec3(0.0, 1.0, 0.0), 30.0,
+        config.width as f32 / config.height as f32, 0.0);
+
+    render_animation("frames", 48, Background::sky(), config, |t| {
+        let mut objs = HittableList::new();
+        objs.add(Sphere::new(vec3(0.0, -1000.0, 0.0), 1000.0,
+            Lambertian::new(Checker::color(vec4(0.2, 0.3, 0.1, 1.0), vec4(0.9, 0.9, 0.9, 1.0)))
+        ));
+        //RotateY turns around the origin, so the box is built centered on the y axis
+        let cube = Cuboid::new(vec3(-0.5, 0.0, -0.5), vec3(0.5, 1.0, 0.5), Metal::color(vec4(0.8, 0.6, 0.2, 1.0), 0.1));
+        objs.add(RotateY::new(cube, t * 2.0 * std::f32::consts::PI));
+
+        let light = || Sphere::new(vec3(4.0, 6.0, 4.0), 1.0, DiffuseLight::color(vec4(4.0, 4.0, 4.0, 1.0)));
+        objs.add(light());
+        let mut lights = HittableList::new();
+        lights.add(light());
+        (camera, objs, lights)
+    })
+}
@@ -1,3 +1,4 @@
+use bevy_math::Vec3;
 use crate::types::{Point3, Ray};
 
 #[derive(Copy, Clone)]
@@ -20,6 +21,22 @@ impl AABB {
         Self::new(min, max)
     }
 
+    //Grows every axis thinner than min_width to min_width around its center, so flat shapes still have a volume
+    pub fn pad(self, min_width: f32) -> Self {
+        let center = self.centroid();
+        let half = (self.max - self.min).max(Vec3::splat(min_width)) * 0.5;
+        Self::new(self.min.min(center - half), self.max.max(center + half))
+    }
+
+    pub fn centroid(&self) -> Point3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.max - self.min;
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
     pub fn hit(&self, ray: &Ray, mut t_min: f32, mut t_max: f32) -> bool {
         let min = self.min.to_array();
         let max = self.max.to_array();
@@ -27,6 +44,13 @@ impl AABB {
         let direction = ray.direction.to_array();
 
         for i in 0..3 {
+            //parallel to the slab, 1 / 0 would give infinities and 0 * inf a NaN when the origin is on its boundary
+            if direction[i] == 0.0 {
+                if origin[i] < min[i] || origin[i] > max[i] {
+                    return false;
+                }
+                continue;
+            }
             let inv_d = 1.0 / direction[i];
             let mut t0 = (min[i] - origin[i]) * inv_d;
             let mut t1 = (max[i] - origin[i]) * inv_d;
@@ -43,3 +67,24 @@ impl AABB {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bevy_math::vec3;
+    use super::*;
+
+    #[test]
+    fn axis_aligned_rays_graze_faces() {
+        let aabb = AABB::new(Vec3::ZERO, Vec3::ONE);
+        let hit = |origin, direction| aabb.hit(&Ray::new(origin, direction, 0.0), 0.001, f32::INFINITY);
+        //sliding along the top and bottom faces, where 0 * inf used to be NaN
+        assert!(hit(vec3(-1.0, 1.0, 0.5), Vec3::X));
+        assert!(hit(vec3(2.0, 0.0, 0.5), -Vec3::X));
+        assert!(hit(vec3(0.0, 0.5, -1.0), Vec3::Z));
+        //just outside them
+        assert!(!hit(vec3(-1.0, 1.0001, 0.5), Vec3::X));
+        assert!(!hit(vec3(2.0, -0.0001, 0.5), -Vec3::X));
+        //pointing away
+        assert!(!hit(vec3(-1.0, 1.0, 0.5), -Vec3::X));
+    }
+}
@@ -1,8 +1,22 @@
-use bevy_math::vec3;
+use bevy_math::{Vec3, vec3};
+use rand::Rng;
+use rand::rngs::SmallRng;
 use crate::aabb::AABB;
 use crate::material::Material;
-use crate::obj::{HitResult, Hittable};
-use crate::types::Ray;
+use crate::obj::{HitResult, Hittable, is_solid};
+use crate::types::{Point3, Ray};
+use crate::util::{Onb, random_in_unit_disk, unit_vector};
+
+//Converts the uniform area density of a flat shape into a solid angle density as seen from origin
+fn rect_pdf_value(rect: &impl Hittable, area: f32, origin: Point3, direction: Vec3, rng: &mut SmallRng) -> f32 {
+    let hit = match rect.hit(&Ray::new(origin, direction, 0.0), 0.001, f32::INFINITY, rng) {
+        Some(hit) => hit,
+        None => return 0.0,
+    };
+    let distance_squared = hit.t * hit.t * direction.length_squared();
+    let cosine = (direction.dot(hit.normal) / direction.length()).abs();
+    distance_squared / (cosine * area)
+}
 
 pub struct XYRect<T: Material> {
     material: T,
@@ -27,7 +41,10 @@ impl<T: Material> XYRect<T> {
 }
 
 impl<T: Material> Hittable for XYRect<T> {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitResult> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
+        if ray.direction.z.abs() < 1e-8 {
+            return None;
+        }
         let t = (self.z - ray.origin.z) / ray.direction.z;
         if t < t_min || t > t_max {
             return None;
@@ -49,12 +66,13 @@ impl<T: Material> Hittable for XYRect<T> {
         let position = ray.at(t);
         let u = (x - self.x0) / (self.x1 - self.x0);
         let v = (y - self.y0) / (self.y1 - self.y0);
-        if !self.material.hack_solid(u, v, position) {
+        if !is_solid(&self.material, u, v, position, rng) {
             return None;
         }
 
         Some(HitResult {
             position,
+            local: position,
             normal,
             t,
             front_face,
@@ -65,10 +83,15 @@ impl<T: Material> Hittable for XYRect<T> {
     }
 
     fn bounding_box(&self) -> AABB {
-        AABB::new(
-            vec3(self.x0, self.y0, self.z - 0.0001),
-            vec3(self.x1, self.y1, self.z + 0.0001),
-        )
+        AABB::new(vec3(self.x0, self.y0, self.z), vec3(self.x1, self.y1, self.z)).pad(0.0002)
+    }
+
+    fn pdf_value(&self, origin: Point3, direction: Vec3, rng: &mut SmallRng) -> f32 {
+        rect_pdf_value(self, (self.x1 - self.x0) * (self.y1 - self.y0), origin, direction, rng)
+    }
+
+    fn random(&self, origin: Point3, rng: &mut SmallRng) -> Vec3 {
+        vec3(rng.gen_range(self.x0..=self.x1), rng.gen_range(self.y0..=self.y1), self.z) - origin
     }
 }
 
@@ -95,7 +118,10 @@ impl<T: Material> XZRect<T> {
 }
 
 impl<T: Material> Hittable for XZRect<T> {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitResult> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
+        if ray.direction.y.abs() < 1e-8 {
+            return None;
+        }
         let t = (self.y - ray.origin.y) / ray.direction.y;
         if t < t_min || t > t_max {
             return None;
@@ -117,12 +143,13 @@ impl<T: Material> Hittable for XZRect<T> {
         let position = ray.at(t);
         let u = (x - self.x0) / (self.x1 - self.x0);
         let v = (z - self.z0) / (self.z1 - self.z0);
-        if !self.material.hack_solid(u, v, position) {
+        if !is_solid(&self.material, u, v, position, rng) {
             return None;
         }
 
         Some(HitResult {
             position,
+            local: position,
             normal,
             t,
             front_face,
@@ -133,10 +160,15 @@ impl<T: Material> Hittable for XZRect<T> {
     }
 
     fn bounding_box(&self) -> AABB {
-        AABB::new(
-            vec3(self.x0, self.y - 0.0001, self.z0),
-            vec3(self.x1, self.y + 0.0001, self.z1),
-        )
+        AABB::new(vec3(self.x0, self.y, self.z0), vec3(self.x1, self.y, self.z1)).pad(0.0002)
+    }
+
+    fn pdf_value(&self, origin: Point3, direction: Vec3, rng: &mut SmallRng) -> f32 {
+        rect_pdf_value(self, (self.x1 - self.x0) * (self.z1 - self.z0), origin, direction, rng)
+    }
+
+    fn random(&self, origin: Point3, rng: &mut SmallRng) -> Vec3 {
+        vec3(rng.gen_range(self.x0..=self.x1), self.y, rng.gen_range(self.z0..=self.z1)) - origin
     }
 }
 
@@ -163,7 +195,10 @@ impl<T: Material> YZRect<T> {
 }
 
 impl<T: Material> Hittable for YZRect<T> {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitResult> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
+        if ray.direction.x.abs() < 1e-8 {
+            return None;
+        }
         let t = (self.x - ray.origin.x) / ray.direction.x;
         if t < t_min || t > t_max {
             return None;
@@ -185,12 +220,13 @@ impl<T: Material> Hittable for YZRect<T> {
         let position = ray.at(t);
         let u = (y - self.y0) / (self.y1 - self.y0);
         let v = (z - self.z0) / (self.z1 - self.z0);
-        if !self.material.hack_solid(u, v, position) {
+        if !is_solid(&self.material, u, v, position, rng) {
             return None;
         }
 
         Some(HitResult {
             position,
+            local: position,
             normal,
             t,
             front_face,
@@ -201,9 +237,292 @@ impl<T: Material> Hittable for YZRect<T> {
     }
 
     fn bounding_box(&self) -> AABB {
-        AABB::new(
-            vec3(self.x - 0.0001, self.y0, self.z0),
-            vec3(self.x + 0.0001, self.y1, self.z1),
-        )
+        AABB::new(vec3(self.x, self.y0, self.z0), vec3(self.x, self.y1, self.z1)).pad(0.0002)
+    }
+
+    fn pdf_value(&self, origin: Point3, direction: Vec3, rng: &mut SmallRng) -> f32 {
+        rect_pdf_value(self, (self.y1 - self.y0) * (self.z1 - self.z0), origin, direction, rng)
+    }
+
+    fn random(&self, origin: Point3, rng: &mut SmallRng) -> Vec3 {
+        vec3(self.x, rng.gen_range(self.y0..=self.y1), rng.gen_range(self.z0..=self.z1)) - origin
+    }
+}
+
+//Parallelogram with corner q and edges u and v, can be oriented any way
+pub struct Quad<T: Material> {
+    material: T,
+    q: Point3,
+    u: Vec3,
+    v: Vec3,
+    normal: Vec3,
+    d: f32,
+    //cross(u, v) / |cross(u, v)|^2, turns plane points into (alpha, beta) coordinates
+    w: Vec3,
+    area: f32,
+}
+
+impl<T: Material> Quad<T> {
+    pub fn new(q: Point3, u: Vec3, v: Vec3, material: T) -> Self {
+        let n = u.cross(v);
+        let normal = unit_vector(n);
+        Self {
+            material,
+            q,
+            u,
+            v,
+            normal,
+            d: normal.dot(q),
+            w: n / n.dot(n),
+            area: n.length(),
+        }
+    }
+}
+
+impl<T: Material> Hittable for Quad<T> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
+        let denom = self.normal.dot(ray.direction);
+        if denom.abs() < 1e-8 {
+            return None;
+        }
+        let t = (self.d - self.normal.dot(ray.origin)) / denom;
+        if t < t_min || t > t_max {
+            return None;
+        }
+
+        let position = ray.at(t);
+        let planar = position - self.q;
+        let alpha = self.w.dot(planar.cross(self.v));
+        let beta = self.w.dot(self.u.cross(planar));
+        if !(0.0..=1.0).contains(&alpha) || !(0.0..=1.0).contains(&beta) {
+            return None;
+        }
+
+        let front_face = denom < 0.0;
+        let normal = if front_face {
+            self.normal
+        } else {
+            -self.normal
+        };
+
+        if !is_solid(&self.material, alpha, beta, position, rng) {
+            return None;
+        }
+
+        Some(HitResult {
+            position,
+            local: position,
+            normal,
+            t,
+            front_face,
+            material: &self.material,
+            u: alpha,
+            v: beta,
+        })
+    }
+
+    fn bounding_box(&self) -> AABB {
+        let a = AABB::new(self.q.min(self.q + self.u + self.v), self.q.max(self.q + self.u + self.v));
+        let b = AABB::new((self.q + self.u).min(self.q + self.v), (self.q + self.u).max(self.q + self.v));
+        //pad so axis aligned quads still get a volume
+        AABB::surrounding_box(a, b).pad(0.0002)
+    }
+
+    fn pdf_value(&self, origin: Point3, direction: Vec3, rng: &mut SmallRng) -> f32 {
+        rect_pdf_value(self, self.area, origin, direction, rng)
+    }
+
+    fn random(&self, origin: Point3, rng: &mut SmallRng) -> Vec3 {
+        self.q + rng.gen::<f32>() * self.u + rng.gen::<f32>() * self.v - origin
+    }
+}
+
+pub struct Disk<T: Material> {
+    material: T,
+    center: Point3,
+    normal: Vec3,
+    radius: f32,
+    //in plane axes, u = 0 points along the first one
+    frame: Onb,
+}
+
+impl<T: Material> Disk<T> {
+    pub fn new(center: Point3, normal: Vec3, radius: f32, material: T) -> Self {
+        let normal = unit_vector(normal);
+        Self {
+            material,
+            center,
+            normal,
+            radius,
+            frame: Onb::from_w(normal),
+        }
+    }
+}
+
+impl<T: Material> Hittable for Disk<T> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
+        let denom = self.normal.dot(ray.direction);
+        if denom.abs() < 1e-8 {
+            return None;
+        }
+        let t = (self.center - ray.origin).dot(self.normal) / denom;
+        if t < t_min || t > t_max {
+            return None;
+        }
+        let position = ray.at(t);
+        let offset = position - self.center;
+        let r2 = offset.length_squared();
+        if r2 > self.radius * self.radius {
+            return None;
+        }
+
+        let front_face = denom < 0.0;
+        let normal = if front_face {
+            self.normal
+        } else {
+            -self.normal
+        };
+
+        //polar coordinates, so textures map radially
+        let angle = offset.dot(self.frame.v).atan2(offset.dot(self.frame.u));
+        let u = angle.rem_euclid(2.0 * std::f32::consts::PI) / (2.0 * std::f32::consts::PI);
+        let v = r2.sqrt() / self.radius;
+        if !is_solid(&self.material, u, v, position, rng) {
+            return None;
+        }
+
+        Some(HitResult {
+            position,
+            local: position,
+            normal,
+            t,
+            front_face,
+            material: &self.material,
+            u,
+            v,
+        })
+    }
+
+    fn bounding_box(&self) -> AABB {
+        //how far the rim reaches along each axis, padded so the box is never flat
+        let n = self.normal;
+        let extent = self.radius * vec3(
+            (1.0 - n.x * n.x).max(0.0).sqrt(),
+            (1.0 - n.y * n.y).max(0.0).sqrt(),
+            (1.0 - n.z * n.z).max(0.0).sqrt(),
+        );
+        AABB::new(self.center - extent, self.center + extent).pad(0.0002)
+    }
+
+    fn pdf_value(&self, origin: Point3, direction: Vec3, rng: &mut SmallRng) -> f32 {
+        let area = std::f32::consts::PI * self.radius * self.radius;
+        rect_pdf_value(self, area, origin, direction, rng)
+    }
+
+    fn random(&self, origin: Point3, rng: &mut SmallRng) -> Vec3 {
+        let p = self.radius * random_in_unit_disk(rng);
+        self.center + p.x * self.frame.u + p.y * self.frame.v - origin
+    }
+}
+
+//Half the size of the box reported for infinite planes
+pub const PLANE_EXTENT: f32 = 1.0e6;
+
+//Infinite plane through point with the given normal. It has no real bounding box, so it reports a
+//PLANE_EXTENT sized one, which would make it the root of any BVH it's put in. Add planes directly to the
+//top level HittableList instead of a BvhNode, HittableList::accelerate keeps them out of the tree it builds
+pub struct Plane<T: Material> {
+    material: T,
+    point: Point3,
+    normal: Vec3,
+    //in plane axes, u and v are the distances along them from point (use Wrap::Repeat for image textures)
+    frame: Onb,
+}
+
+impl<T: Material> Plane<T> {
+    pub fn new(point: Point3, normal: Vec3, material: T) -> Self {
+        let normal = unit_vector(normal);
+        Self {
+            material,
+            point,
+            normal,
+            frame: Onb::from_w(normal),
+        }
+    }
+}
+
+impl<T: Material> Hittable for Plane<T> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
+        let denom = self.normal.dot(ray.direction);
+        if denom.abs() < 1e-8 {
+            return None;
+        }
+        let t = (self.point - ray.origin).dot(self.normal) / denom;
+        if t < t_min || t > t_max {
+            return None;
+        }
+
+        let position = ray.at(t);
+        let offset = position - self.point;
+        let u = offset.dot(self.frame.u);
+        let v = offset.dot(self.frame.v);
+
+        let front_face = denom < 0.0;
+        let normal = if front_face {
+            self.normal
+        } else {
+            -self.normal
+        };
+
+        if !is_solid(&self.material, u, v, position, rng) {
+            return None;
+        }
+
+        Some(HitResult {
+            position,
+            local: position,
+            normal,
+            t,
+            front_face,
+            material: &self.material,
+            u,
+            v,
+        })
+    }
+
+    fn bounding_box(&self) -> AABB {
+        AABB::new(Vec3::splat(-PLANE_EXTENT), Vec3::splat(PLANE_EXTENT))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_math::vec4;
+    use rand::SeedableRng;
+    use crate::material::Lambertian;
+    use crate::texture::SolidColor;
+    use super::*;
+
+    fn gray() -> Lambertian<SolidColor> {
+        Lambertian::color(vec4(0.5, 0.5, 0.5, 1.0))
+    }
+
+    #[test]
+    fn parallel_rays_miss() {
+        let mut rng = SmallRng::seed_from_u64(80);
+        //rays inside the plane of each primitive, where the division by the direction would give NaNs
+        let xy = XYRect::new(-1.0, 1.0, -1.0, 1.0, 0.0, gray());
+        assert!(xy.hit(&Ray::new(vec3(-2.0, 0.0, 0.0), Vec3::X, 0.0), 0.001, f32::INFINITY, &mut rng).is_none());
+        let xz = XZRect::new(-1.0, 1.0, -1.0, 1.0, 0.0, gray());
+        assert!(xz.hit(&Ray::new(vec3(-2.0, 0.0, 0.0), Vec3::X, 0.0), 0.001, f32::INFINITY, &mut rng).is_none());
+        let yz = YZRect::new(-1.0, 1.0, -1.0, 1.0, 0.0, gray());
+        assert!(yz.hit(&Ray::new(vec3(0.0, -2.0, 0.0), Vec3::Y, 0.0), 0.001, f32::INFINITY, &mut rng).is_none());
+        let plane = Plane::new(Vec3::ZERO, Vec3::Y, gray());
+        assert!(plane.hit(&Ray::new(Vec3::ZERO, Vec3::Z, 0.0), 0.001, f32::INFINITY, &mut rng).is_none());
+        //and just above it
+        assert!(xz.hit(&Ray::new(vec3(-2.0, 0.5, 0.0), Vec3::X, 0.0), 0.001, f32::INFINITY, &mut rng).is_none());
+
+        //a ray that isn't parallel still hits
+        assert!(xz.hit(&Ray::new(vec3(0.0, 1.0, 0.0), -Vec3::Y, 0.0), 0.001, f32::INFINITY, &mut rng).is_some());
     }
 }
@@ -0,0 +1,177 @@
+use std::f32::consts::PI;
+use std::sync::Arc;
+use bevy_math::{Vec3, vec3, vec4};
+use image::RgbaImage;
+use rand::Rng;
+use rand::rngs::SmallRng;
+use crate::types::{Color, linear, luminance};
+use crate::util::unit_vector;
+
+//What rays that don't hit anything see
+#[derive(Clone)]
+pub enum Background {
+    Solid(Color),
+    //Blends from bottom (looking straight down) to top (looking straight up)
+    Gradient { top: Color, bottom: Color },
+    //Equirectangular map, the center of the image is towards -z
+    Environment(EnvironmentMap),
+    //Any function of the ray direction, for skies that don't fit the other variants
+    Procedural(Arc<dyn Fn(Vec3) -> Color + Send + Sync>),
+}
+
+const SKY_TOP: Color = Color::new(0.5, 0.7, 1.0, 1.0);
+const SKY_BOTTOM: Color = Color::new(1.0, 1.0, 1.0, 1.0);
+
+//Classic white to blue sky
+pub fn sky_color(direction: Vec3) -> Color {
+    let t = 0.5 * (unit_vector(direction).y + 1.0);
+    SKY_BOTTOM.lerp(SKY_TOP, t)
+}
+
+impl Background {
+    pub fn sky() -> Self {
+        Background::Gradient { top: SKY_TOP, bottom: SKY_BOTTOM }
+    }
+
+    pub fn environment(image: RgbaImage) -> Self {
+        Background::Environment(EnvironmentMap::new(image))
+    }
+
+    pub fn procedural(f: impl Fn(Vec3) -> Color + Send + Sync + 'static) -> Self {
+        Background::Procedural(Arc::new(f))
+    }
+
+    pub fn color(&self, direction: Vec3) -> Color {
+        match self {
+            Background::Solid(color) => *color,
+            Background::Gradient { top, bottom } => {
+                let t = 0.5 * (unit_vector(direction).y + 1.0);
+                bottom.lerp(*top, t)
+            }
+            Background::Environment(map) => map.color(direction),
+            Background::Procedural(f) => f(direction),
+        }
+    }
+}
+
+//Equirectangular image that also knows how bright each of its pixels is, so the renderer can send rays
+//towards bright spots like the sun instead of hoping to hit them by chance
+#[derive(Clone)]
+pub struct EnvironmentMap {
+    image: RgbaImage,
+    //Cumulative distribution over the rows, and over the pixels of each row, picking pixels proportionally
+    //to their brightness times the solid angle they cover
+    row_cdf: Vec<f32>,
+    pixel_cdf: Vec<f32>,
+    //Density of each pixel being picked, per unit of uv area
+    density: Vec<f32>,
+}
+
+//Index of the bin x falls in, empty bins are never picked
+fn sample_cdf(cdf: &[f32], x: f32) -> usize {
+    cdf.partition_point(|&c| c <= x).min(cdf.len() - 1)
+}
+
+impl EnvironmentMap {
+    pub fn new(image: RgbaImage) -> Self {
+        let (width, height) = (image.width() as usize, image.height() as usize);
+
+        let mut weights = vec![0.0; width * height];
+        for j in 0..height {
+            //rows near the poles are squeezed into a smaller solid angle
+            let sin_theta = ((j as f32 + 0.5) / height as f32 * PI).sin();
+            for i in 0..width {
+                weights[j * width + i] = luminance(linear(texel(&image, i as u32, j as u32))) * sin_theta;
+            }
+        }
+        //a black image would give an empty distribution, pick by solid angle instead
+        if weights.iter().sum::<f32>() <= 0.0 {
+            for j in 0..height {
+                let sin_theta = ((j as f32 + 0.5) / height as f32 * PI).sin();
+                weights[j * width..(j + 1) * width].fill(sin_theta);
+            }
+        }
+        let total: f32 = weights.iter().sum();
+
+        let mut row_cdf = Vec::with_capacity(height);
+        let mut pixel_cdf = Vec::with_capacity(width * height);
+        let mut rows = 0.0;
+        for row in weights.chunks(width) {
+            let row_total: f32 = row.iter().sum();
+            let mut sum = 0.0;
+            for &w in row {
+                sum += w;
+                pixel_cdf.push(if row_total > 0.0 { sum / row_total } else { 1.0 });
+            }
+            rows += row_total;
+            row_cdf.push(rows / total);
+        }
+        let density = weights.iter().map(|w| w / total * (width * height) as f32).collect();
+
+        Self {
+            image,
+            row_cdf,
+            pixel_cdf,
+            density,
+        }
+    }
+
+    //Bilinear interpolation between the 4 nearest pixel centers, wrapping around horizontally
+    pub fn color(&self, direction: Vec3) -> Color {
+        let (width, height) = (self.image.width(), self.image.height());
+        let (u, v) = uv(direction);
+
+        let x = u * width as f32 - 0.5;
+        let y = (v * height as f32 - 0.5).clamp(0.0, (height - 1) as f32);
+        let (tx, ty) = (x - x.floor(), y - y.floor());
+        let x0 = (x.floor() as i64).rem_euclid(width as i64) as u32;
+        let x1 = (x0 + 1) % width;
+        let y0 = y.floor() as u32;
+        let y1 = (y0 + 1).min(height - 1);
+
+        let top = texel(&self.image, x0, y0).lerp(texel(&self.image, x1, y0), tx);
+        let bottom = texel(&self.image, x0, y1).lerp(texel(&self.image, x1, y1), tx);
+        top.lerp(bottom, ty)
+    }
+
+    //Random direction, brighter parts of the map are picked more often
+    pub fn random(&self, rng: &mut SmallRng) -> Vec3 {
+        let width = self.image.width() as usize;
+        let j = sample_cdf(&self.row_cdf, rng.gen());
+        let i = sample_cdf(&self.pixel_cdf[j * width..(j + 1) * width], rng.gen());
+
+        let u = (i as f32 + rng.gen::<f32>()) / width as f32;
+        let v = (j as f32 + rng.gen::<f32>()) / self.image.height() as f32;
+        let phi = u * 2.0 * PI - PI;
+        let theta = v * PI;
+        vec3(theta.sin() * phi.sin(), theta.cos(), -theta.sin() * phi.cos())
+    }
+
+    //Density over solid angle of `random` returning `direction`
+    pub fn pdf_value(&self, direction: Vec3) -> f32 {
+        let (width, height) = (self.image.width(), self.image.height());
+        let (u, v) = uv(direction);
+        let sin_theta = (v * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        let i = ((u * width as f32) as u32).min(width - 1);
+        let j = ((v * height as f32) as u32).min(height - 1);
+        //the uv square maps to 2pi * pi radians of longitude and latitude
+        self.density[(j * width + i) as usize] / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+//Position of a direction on the map, u goes around the vertical axis and v from straight up to straight down
+fn uv(direction: Vec3) -> (f32, f32) {
+    let d = unit_vector(direction);
+    let u = (d.x.atan2(-d.z) + PI) / (2.0 * PI);
+    let v = d.y.clamp(-1.0, 1.0).acos() / PI;
+    (u, v)
+}
+
+fn texel(image: &RgbaImage, i: u32, j: u32) -> Color {
+    let scale = 1.0 / 255.0;
+    let pixel = image.get_pixel(i, j).0;
+    vec4(pixel[0] as f32 * scale, pixel[1] as f32 * scale, pixel[2] as f32 * scale, 1.0)
+}
@@ -1,25 +1,136 @@
+use std::cell::Cell;
 use std::cmp::Ordering;
 use std::sync::Arc;
-use rand::Rng;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use rand::rngs::SmallRng;
 use crate::aabb::AABB;
 use crate::obj::{HitResult, Hittable};
 use crate::types::Ray;
 
-pub struct BvhNode {
-    left: Arc<dyn Hittable>,
-    right: Arc<dyn Hittable>,
-    bbox: AABB,
+pub enum BvhNode {
+    //Only used when building from a single object, larger trees put objects directly in branches
+    Leaf(Arc<dyn Hittable + Send + Sync>),
+    Branch {
+        left: Arc<dyn Hittable + Send + Sync>,
+        right: Arc<dyn Hittable + Send + Sync>,
+        bbox: AABB,
+    },
 }
 
-unsafe impl Send for BvhNode {}
-unsafe impl Sync for BvhNode {}
+thread_local! {
+    static NODE_VISITS: Cell<u32> = const { Cell::new(0) };
+}
+
+//Off until something needs the counts, so traversal doesn't pay for the thread local otherwise
+static COUNT_NODE_VISITS: AtomicBool = AtomicBool::new(false);
+
+//Starts counting node visits for the rest of the process
+pub fn enable_node_visits() {
+    COUNT_NODE_VISITS.store(true, AtomicOrdering::Relaxed);
+}
+
+//Number of BVH branches the current thread tested rays against since the last reset, for debugging.
+//Always 0 unless enable_node_visits was called
+pub fn node_visits() -> u32 {
+    NODE_VISITS.with(|v| v.get())
+}
+
+pub fn reset_node_visits() {
+    NODE_VISITS.with(|v| v.set(0));
+}
+
+//Sorts the objects along the axis with the lowest surface area heuristic cost (sum of child
+//surface areas weighted by their object count) and returns the index to split them at
+fn sah_sort(objects: &mut [Arc<dyn Hittable + Send + Sync>]) -> usize {
+    let n = objects.len();
+    //sorting indices with precomputed boxes is a lot cheaper than calling bounding_box in every comparison
+    let boxes: Vec<AABB> = objects.iter().map(|o| o.bounding_box()).collect();
+    let centroids: Vec<[f32; 3]> = boxes.iter().map(|b| b.centroid().to_array()).collect();
+    let mut order: Vec<usize> = (0..n).collect();
+    let mut right_area = vec![0.0; n];
+    let mut best: Option<(f32, usize, usize)> = None;
+    for axis in [0, 1, 2] {
+        order.sort_by(|&a, &b| centroids[a][axis].total_cmp(&centroids[b][axis]));
+
+        //right_area[i] is the surface area of the box around order[i..]
+        let mut bbox = boxes[order[n - 1]];
+        for i in (1..n).rev() {
+            bbox = AABB::surrounding_box(bbox, boxes[order[i]]);
+            right_area[i] = bbox.surface_area();
+        }
+
+        let mut left = boxes[order[0]];
+        for i in 1..n {
+            let cost = left.surface_area() * i as f32 + right_area[i] * (n - i) as f32;
+            if best.is_none_or(|(c, _, _)| cost < c) {
+                best = Some((cost, axis, i));
+            }
+            left = AABB::surrounding_box(left, boxes[order[i]]);
+        }
+    }
+    let (_, axis, split) = best.unwrap();
+    if axis != 2 {
+        order.sort_by(|&a, &b| centroids[a][axis].total_cmp(&centroids[b][axis]));
+    }
+    let sorted: Vec<_> = order.iter().map(|&i| objects[i].clone()).collect();
+    objects.clone_from_slice(&sorted);
+    split
+}
+
+//Subtrees with at least this many objects build their halves on separate threads
+const PARALLEL_BUILD_THRESHOLD: usize = 4096;
 
 impl BvhNode {
-    pub fn new(objects: &[Arc<dyn Hittable + Send>]) -> Self {
+    pub fn new(objects: &[Arc<dyn Hittable + Send + Sync>]) -> Self {
         debug_assert_ne!(objects.len(), 0, "List cannot be empty");
+        Self::build(&mut objects.to_vec(), PARALLEL_BUILD_THRESHOLD)
+    }
 
-        let axis = rand::thread_rng().gen_range(0..=2);
-        let cmp = |a: &Arc<dyn Hittable + Send>, b: &Arc<dyn Hittable + Send>| {
+    //Sorts the objects in place instead of copying them at every level
+    fn build(objects: &mut [Arc<dyn Hittable + Send + Sync>], parallel_threshold: usize) -> Self {
+        if objects.len() <= 2 {
+            return Self::new_median(objects);
+        }
+
+        let parallel = objects.len() >= parallel_threshold;
+        let split = sah_sort(objects);
+        let (left, right) = objects.split_at_mut(split);
+        let (left, right) = if parallel {
+            std::thread::scope(|s| {
+                let left = s.spawn(|| Self::build(left, parallel_threshold));
+                let right = Self::build(right, parallel_threshold);
+                (left.join().unwrap(), right)
+            })
+        } else {
+            (Self::build(left, parallel_threshold), Self::build(right, parallel_threshold))
+        };
+        Self::branch(Arc::new(left), Arc::new(right))
+    }
+
+    fn branch(left: Arc<dyn Hittable + Send + Sync>, right: Arc<dyn Hittable + Send + Sync>) -> Self {
+        let bbox = AABB::surrounding_box(left.bounding_box(), right.bounding_box());
+        Self::Branch {
+            left,
+            right,
+            bbox,
+        }
+    }
+
+    //Splits in the middle along the axis the objects are most spread out on,
+    //faster to build than `new` but usually slower to render
+    pub fn new_median(objects: &[Arc<dyn Hittable + Send + Sync>]) -> Self {
+        debug_assert_ne!(objects.len(), 0, "List cannot be empty");
+
+        let mut min = objects[0].bounding_box().centroid();
+        let mut max = min;
+        for o in objects {
+            let c = o.bounding_box().centroid();
+            min = min.min(c);
+            max = max.max(c);
+        }
+        let extent = (max - min).to_array();
+        let axis = (0..3).fold(0, |best, i| if extent[i] > extent[best] { i } else { best });
+        let cmp = |a: &Arc<dyn Hittable + Send + Sync>, b: &Arc<dyn Hittable + Send + Sync>| {
             let a_min = a.bounding_box().min.to_array()[axis];
             let b_min = b.bounding_box().min.to_array()[axis];
             a_min.total_cmp(&b_min)
@@ -27,7 +138,7 @@ impl BvhNode {
 
         let (left, right) = match objects.len() {
             0 => panic!("No objects"),
-            1 => (objects[0].clone(), objects[0].clone()),
+            1 => return Self::Leaf(objects[0].clone()),
             2 => {
                 let a = objects[0].clone();
                 let b = objects[1].clone();
@@ -43,40 +154,193 @@ impl BvhNode {
 
                 let mid = copy.len() / 2;
                 (
-                    Arc::new(Self::new(&copy[..mid])) as _,
-                    Arc::new(Self::new(&copy[mid..])) as _,
+                    Arc::new(Self::new_median(&copy[..mid])) as _,
+                    Arc::new(Self::new_median(&copy[mid..])) as _,
                 )
             }
         };
-        let bbox = AABB::surrounding_box(left.bounding_box(), right.bounding_box());
-        Self {
-            left,
-            right,
-            bbox,
-        }
+        Self::branch(left, right)
     }
 }
 
 impl Hittable for BvhNode {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitResult> {
-        if !self.bbox.hit(ray, t_min, t_max) {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
+        let (left, right, bbox) = match self {
+            Self::Leaf(obj) => return obj.hit(ray, t_min, t_max, rng),
+            Self::Branch { left, right, bbox } => (left, right, bbox),
+        };
+        if COUNT_NODE_VISITS.load(AtomicOrdering::Relaxed) {
+            NODE_VISITS.with(|v| v.set(v.get() + 1));
+        }
+        if !bbox.hit(ray, t_min, t_max) {
             return None;
         }
 
-        let left = self.left.hit(ray, t_min, t_max);
-        if let Some(res) = left.as_ref() {
-            let right = self.right.hit(ray, t_min, res.t);
-            if right.is_some() {
-                right
+        let left_hit = left.hit(ray, t_min, t_max, rng);
+        if let Some(res) = left_hit.as_ref() {
+            let right_hit = right.hit(ray, t_min, res.t, rng);
+            if right_hit.is_some() {
+                right_hit
             } else {
-                left
+                left_hit
             }
         } else {
-            self.right.hit(ray, t_min, t_max)
+            right.hit(ray, t_min, t_max, rng)
         }
     }
 
     fn bounding_box(&self) -> AABB {
-        self.bbox
+        match self {
+            Self::Leaf(obj) => obj.bounding_box(),
+            Self::Branch { bbox, .. } => *bbox,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_math::{vec3, vec4};
+    use rand::{Rng, SeedableRng};
+    use crate::material::Lambertian;
+    use std::sync::atomic::AtomicU32;
+    use bevy_math::Vec3;
+    use crate::obj::Sphere;
+    use crate::texture::SolidColor;
+    use crate::util::random_unit_vector;
+    use super::*;
+
+    //Nine spheres near the origin and three far away along x
+    fn clusters() -> Vec<Arc<dyn Hittable + Send + Sync>> {
+        let near = (0..9).map(|i| vec3(i as f32 * 0.1, 0.0, 0.0));
+        let far = (0..3).map(|i| vec3(100.0 + i as f32 * 0.1, 0.0, 0.0));
+        near.chain(far)
+            .map(|c| Arc::new(Sphere::new(c, 0.05, Lambertian::color(vec4(0.5, 0.5, 0.5, 1.0)))) as _)
+            .collect()
+    }
+
+    fn children(node: &BvhNode) -> (AABB, AABB) {
+        match node {
+            BvhNode::Branch { left, right, .. } => (left.bounding_box(), right.bounding_box()),
+            BvhNode::Leaf(_) => panic!("Expected a branch"),
+        }
+    }
+
+    fn child_area(node: &BvhNode) -> f32 {
+        let (left, right) = children(node);
+        left.surface_area() + right.surface_area()
+    }
+
+    //Nesting and leaf order of a tree built over `objects`, as indices into it like "((0 1) (2 (3 4)))"
+    fn shape(node: &dyn Hittable, objects: &[Arc<dyn Hittable + Send + Sync>]) -> String {
+        let ptr = node as *const dyn Hittable;
+        if let Some(i) = objects.iter().position(|o| std::ptr::addr_eq(Arc::as_ptr(o), ptr)) {
+            return i.to_string();
+        }
+        //SAFETY: everything in the tree that isn't one of the objects was created by the build as a BvhNode
+        match unsafe { &*(ptr as *const BvhNode) } {
+            BvhNode::Leaf(obj) => shape(obj.as_ref(), objects),
+            BvhNode::Branch { left, right, .. } => format!("({} {})", shape(left.as_ref(), objects), shape(right.as_ref(), objects)),
+        }
+    }
+
+    #[test]
+    fn same_objects_give_the_same_tree() {
+        let mut rng = SmallRng::seed_from_u64(23);
+        //pairs of spheres at the same spot, so ties between centroids have to be broken the same way too
+        let objects: Vec<Arc<dyn Hittable + Send + Sync>> = (0..250)
+            .map(|_| vec3(rng.gen_range(-50.0..50.0), rng.gen_range(-50.0..50.0), rng.gen_range(-50.0..50.0)))
+            .flat_map(|center| [center, center])
+            .map(|center| Arc::new(Sphere::new(center, 0.2, Lambertian::color(vec4(0.5, 0.5, 0.5, 1.0)))) as _)
+            .collect();
+
+        let a = shape(&BvhNode::new(&objects), &objects);
+        let b = shape(&BvhNode::new(&objects), &objects);
+        assert_eq!(a, b);
+        //every object shows up once
+        assert_eq!(a.split(|c: char| !c.is_ascii_digit()).filter(|s| !s.is_empty()).count(), objects.len());
+    }
+
+    //Sphere counting how many times rays are tested against it
+    struct Counted {
+        sphere: Sphere<Lambertian<SolidColor>>,
+        hits: AtomicU32,
+    }
+
+    impl Hittable for Counted {
+        fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
+            self.hits.fetch_add(1, AtomicOrdering::Relaxed);
+            self.sphere.hit(ray, t_min, t_max, rng)
+        }
+
+        fn bounding_box(&self) -> AABB {
+            self.sphere.bounding_box()
+        }
+    }
+
+    #[test]
+    fn leaves_are_hit_once() {
+        let counted = |center| Arc::new(Counted {
+            sphere: Sphere::new(center, 0.5, Lambertian::color(vec4(0.5, 0.5, 0.5, 1.0))),
+            hits: AtomicU32::new(0),
+        });
+        let hits = |objects: &[Arc<Counted>]| objects.iter().map(|o| o.hits.load(AtomicOrdering::Relaxed)).sum::<u32>();
+        let mut rng = SmallRng::seed_from_u64(24);
+        let ray = Ray::new(vec3(0.0, 0.0, 10.0), vec3(0.0, 0.0, -1.0), 0.0);
+
+        //a single object used to be put on both sides of a branch and tested twice
+        let one = [counted(Vec3::ZERO)];
+        let tree = BvhNode::new(&[one[0].clone() as Arc<dyn Hittable + Send + Sync>]);
+        assert!(tree.hit(&ray, 0.001, f32::INFINITY, &mut rng).is_some());
+        assert_eq!(hits(&one), 1);
+
+        //a row of spheres along the ray, every one of them is in the way but none is tested more than once
+        let many: Vec<Arc<Counted>> = (0..100).map(|i| counted(vec3(0.0, 0.0, -(i as f32)))).collect();
+        let objects: Vec<Arc<dyn Hittable + Send + Sync>> = many.iter().map(|o| o.clone() as _).collect();
+        let tree = BvhNode::new(&objects);
+        assert!(tree.hit(&ray, 0.001, f32::INFINITY, &mut rng).is_some());
+        assert!(hits(&many) <= 100, "{} hit calls for 100 objects", hits(&many));
+        assert!(many.iter().all(|o| o.hits.load(AtomicOrdering::Relaxed) <= 1));
+    }
+
+    #[test]
+    fn sah_splits_between_clusters() {
+        let objects = clusters();
+        let sah = BvhNode::new(&objects);
+        let (left, right) = children(&sah);
+        assert!(left.max.x < right.min.x || right.max.x < left.min.x);
+        //both children are tight around one cluster
+        assert!(child_area(&sah) < 1.0, "Got area {}", child_area(&sah));
+        //splitting by count puts some of the near spheres with the far ones, so one child spans the whole scene
+        assert!(child_area(&BvhNode::new_median(&objects)) > 10.0 * child_area(&sah));
+    }
+
+    #[test]
+    fn parallel_build_matches_serial() {
+        let mut rng = SmallRng::seed_from_u64(5);
+        let objects: Vec<Arc<dyn Hittable + Send + Sync>> = (0..2 * PARALLEL_BUILD_THRESHOLD)
+            .map(|_| {
+                let center = vec3(rng.gen_range(-50.0..50.0), rng.gen_range(-50.0..50.0), rng.gen_range(-50.0..50.0));
+                Arc::new(Sphere::new(center, 0.2, Lambertian::color(vec4(0.5, 0.5, 0.5, 1.0)))) as _
+            })
+            .collect();
+
+        enable_node_visits();
+        let mut serial_order = objects.clone();
+        let serial = BvhNode::build(&mut serial_order, usize::MAX);
+        let mut parallel_order = objects.clone();
+        let parallel = BvhNode::build(&mut parallel_order, PARALLEL_BUILD_THRESHOLD);
+
+        //the objects end up in leaf order, and the same rays have to visit the same nodes
+        assert!(serial_order.iter().zip(&parallel_order).all(|(a, b)| Arc::ptr_eq(a, b)));
+        for _ in 0..1000 {
+            let ray = Ray::new(vec3(0.0, 0.0, 0.0), random_unit_vector(&mut rng), 0.0);
+            reset_node_visits();
+            let a = serial.hit(&ray, 0.001, f32::INFINITY, &mut rng).map(|h| h.t);
+            let serial_visits = node_visits();
+            reset_node_visits();
+            let b = parallel.hit(&ray, 0.001, f32::INFINITY, &mut rng).map(|h| h.t);
+            assert_eq!(a, b);
+            assert_eq!(serial_visits, node_visits());
+        }
     }
 }
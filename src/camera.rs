@@ -1,8 +1,31 @@
-use bevy_math::Vec3;
+use std::f32::consts::PI;
+use bevy_math::{Quat, Vec3};
+use rand::Rng;
+use rand::rngs::SmallRng;
 use crate::types::{Point3, Ray};
-use crate::util::{random_in_unit_disk, unit_vector};
+use crate::util::{polar_to_xyz, random_in_unit_disk, random_in_unit_disk_concentric, unit_vector};
 
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CameraKind {
+    Perspective,
+    //Parallel rays covering the same area the perspective camera sees at the focus distance
+    Orthographic,
+    //Full 360x180 panorama around the camera, s is the azimuth and t the inclination.
+    //Use a 2:1 aspect ratio for a regular equirectangular map
+    Equirect,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LensSampling {
+    //Uniform points in the aperture by rejection sampling
+    Rejection,
+    //Uniform points in the aperture from the concentric mapping, which never rejects samples
+    Concentric,
+}
+
+#[derive(Copy, Clone)]
 pub struct Camera {
+    kind: CameraKind,
     origin: Point3,
     horizontal: Vec3,
     vertical: Vec3,
@@ -11,6 +34,9 @@ pub struct Camera {
     v: Vec3,
     w: Vec3,
     lens_radius: f32,
+    lens_sampling: LensSampling,
+    time0: f32,
+    time1: f32,
 }
 
 impl Camera {
@@ -21,8 +47,17 @@ impl Camera {
         let viewport_height = 2.0 * h;
         let viewport_width = aspect_ratio * viewport_height;
 
-        let w = unit_vector(look_from - look_at);
-        let u = unit_vector(vup.cross(w));
+        //a camera looking at its own position looks down -z, and one whose up vector is parallel
+        //to the view direction picks another axis as up, instead of filling the image with NaNs
+        let mut w = unit_vector(look_from - look_at);
+        if w == Vec3::ZERO {
+            w = Vec3::Z;
+        }
+        let mut u = unit_vector(vup.cross(w));
+        if u == Vec3::ZERO {
+            let a = if w.x.abs() > 0.9 { Vec3::Y } else { Vec3::X };
+            u = unit_vector(a.cross(w));
+        }
         let v = w.cross(u);
 
         let origin = look_from;
@@ -33,6 +68,7 @@ impl Camera {
         let lens_radius = aperture / 2.0;
 
         Self {
+            kind: CameraKind::Perspective,
             origin,
             horizontal,
             vertical,
@@ -41,15 +77,166 @@ impl Camera {
             v,
             w,
             lens_radius,
+            lens_sampling: LensSampling::Concentric,
+            time0: 0.0,
+            time1: 0.0,
         }
     }
 
-    pub fn ray(&self, s: f32, t: f32) -> Ray {
-        let rd = self.lens_radius * random_in_unit_disk();
-        let offset = self.u * rd.x + self.v * rd.y;
-        Ray::new(
-            self.origin + offset,
-            self.lower_left_corner + s * self.horizontal + t * self.vertical - self.origin - offset
-        )
+    //Focuses on look_at, wherever it is
+    pub fn with_autofocus(look_from: Point3, look_at: Point3, vup: Vec3, vfov: f32, aspect_ratio: f32, aperture: f32) -> Self {
+        Self::new(look_from, look_at, vup, vfov, aspect_ratio, aperture, (look_at - look_from).length())
+    }
+
+    //Pinhole camera on a sphere around `center` looking at it, `azimuth` radians around the y axis starting at x
+    //and `elevation` radians above the horizon. Stepping the azimuth gives a turntable animation
+    pub fn orbit(center: Point3, radius: f32, azimuth: f32, elevation: f32, vfov: f32, aspect_ratio: f32) -> Self {
+        let look_from = center + polar_to_xyz(radius, PI / 2.0 - elevation, azimuth);
+        Self::new(look_from, center, Vec3::Y, vfov, aspect_ratio, 0.0, radius)
+    }
+
+    //Rays are sent at a random time between time0 and time1, objects moving during that interval get motion blur
+    pub fn with_shutter(mut self, time0: f32, time1: f32) -> Self {
+        self.time0 = time0;
+        self.time1 = time1;
+        self
+    }
+
+    pub fn with_kind(mut self, kind: CameraKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    pub fn with_lens_sampling(mut self, lens_sampling: LensSampling) -> Self {
+        self.lens_sampling = lens_sampling;
+        self
+    }
+
+    //Changes the width of the view to match a new aspect ratio, keeping the vertical field of view
+    pub fn with_aspect_ratio(mut self, aspect_ratio: f32) -> Self {
+        let center = self.lower_left_corner + self.horizontal / 2.0 + self.vertical / 2.0;
+        self.horizontal = self.vertical.length() * aspect_ratio * self.u;
+        self.lower_left_corner = center - self.horizontal / 2.0 - self.vertical / 2.0;
+        self
+    }
+
+    //Moves the focus plane through `point`, keeping the field of view
+    pub fn focus_on(mut self, point: Point3) -> Self {
+        let focus = (self.origin - self.lower_left_corner).dot(self.w);
+        let scale = (point - self.origin).dot(self.forward()) / focus;
+        self.lower_left_corner = self.origin + (self.lower_left_corner - self.origin) * scale;
+        self.horizontal *= scale;
+        self.vertical *= scale;
+        self
+    }
+
+    pub fn translated(mut self, offset: Vec3) -> Self {
+        self.origin += offset;
+        self.lower_left_corner += offset;
+        self
+    }
+
+    //Turns the camera around its position, `yaw` around the world y axis and `pitch` around its own horizontal axis
+    pub fn rotated(mut self, yaw: f32, pitch: f32) -> Self {
+        let rotation = Quat::from_rotation_y(yaw) * Quat::from_axis_angle(self.u, pitch);
+        let corner = rotation * (self.lower_left_corner - self.origin);
+        self.lower_left_corner = self.origin + corner;
+        self.horizontal = rotation * self.horizontal;
+        self.vertical = rotation * self.vertical;
+        self.u = rotation * self.u;
+        self.v = rotation * self.v;
+        self.w = rotation * self.w;
+        self
+    }
+
+    //Direction the camera looks towards, and the horizontal direction to its right
+    pub fn forward(&self) -> Vec3 {
+        -self.w
+    }
+
+    pub fn right(&self) -> Vec3 {
+        self.u
+    }
+
+    pub fn ray(&self, s: f32, t: f32, rng: &mut SmallRng) -> Ray {
+        let time = if self.time0 < self.time1 {
+            rng.gen_range(self.time0..self.time1)
+        } else {
+            self.time0
+        };
+        match self.kind {
+            CameraKind::Perspective => {
+                //a pinhole camera doesn't need a point on the lens
+                let offset = if self.lens_radius > 0.0 {
+                    let rd = self.lens_radius * match self.lens_sampling {
+                        LensSampling::Rejection => random_in_unit_disk(rng),
+                        LensSampling::Concentric => random_in_unit_disk_concentric(rng),
+                    };
+                    self.u * rd.x + self.v * rd.y
+                } else {
+                    Vec3::ZERO
+                };
+                Ray::new(
+                    self.origin + offset,
+                    self.lower_left_corner + s * self.horizontal + t * self.vertical - self.origin - offset,
+                    time,
+                )
+            }
+            CameraKind::Orthographic => {
+                //lower_left_corner lies on the focus plane, move it back to the camera
+                let focus = (self.origin - self.lower_left_corner).dot(self.w);
+                Ray::new(
+                    self.lower_left_corner + s * self.horizontal + t * self.vertical + focus * self.w,
+                    -self.w,
+                    time,
+                )
+            }
+            CameraKind::Equirect => {
+                //azimuth pi looks towards look_at, t = 1 is straight up
+                let phi = s * 2.0 * PI;
+                let theta = (1.0 - t) * PI;
+                let direction = theta.sin() * (phi.cos() * self.w - phi.sin() * self.u) + theta.cos() * self.v;
+                Ray::new(self.origin, direction, time)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_math::vec3;
+    use rand::SeedableRng;
+    use super::*;
+
+    #[test]
+    fn degenerate_basis_falls_back() {
+        let mut rng = SmallRng::seed_from_u64(79);
+        let cameras = [
+            Camera::new(Vec3::ONE, Vec3::ONE, Vec3::Y, 40.0, 1.0, 0.1, 1.0),
+            Camera::new(vec3(0.0, 5.0, 0.0), Vec3::ZERO, Vec3::Y, 40.0, 1.0, 0.1, 5.0),
+            Camera::new(vec3(0.0, 0.0, 5.0), Vec3::ZERO, Vec3::ZERO, 40.0, 1.0, 0.1, 5.0),
+        ];
+        for camera in cameras {
+            let ray = camera.ray(0.3, 0.7, &mut rng);
+            assert!(ray.origin.is_finite() && ray.direction.is_finite() && ray.direction != Vec3::ZERO);
+        }
+        //still looks at look_at when only the up vector is wrong
+        let forward = cameras[1].forward();
+        assert!((forward - vec3(0.0, -1.0, 0.0)).length() < 1e-5, "Got {}", forward);
+    }
+
+    #[test]
+    fn orbit_looks_at_the_center() {
+        let center = vec3(1.0, 2.0, -3.0);
+        let mut rng = SmallRng::seed_from_u64(90);
+        for (azimuth, elevation) in [(0.0, 0.0), (1.0, 0.5), (4.0, -0.3), (PI, 1.2)] {
+            let camera = Camera::orbit(center, 5.0, azimuth, elevation, 40.0, 1.5);
+            assert!(((camera.origin - center).length() - 5.0).abs() < 1e-4);
+            //the ray through the middle of the image goes straight through the center
+            let ray = camera.ray(0.5, 0.5, &mut rng);
+            assert!((ray.origin + ray.direction - center).length() < 1e-4, "Got {}", ray.origin + ray.direction);
+            let up = (camera.origin - center).y;
+            assert!((up - 5.0 * elevation.sin()).abs() < 1e-4);
+        }
     }
 }
@@ -0,0 +1,16 @@
+pub mod types;
+pub mod obj;
+pub mod camera;
+pub mod material;
+pub mod util;
+pub mod aabb;
+pub mod bvh;
+pub mod texture;
+pub mod perlin;
+pub mod aarect;
+pub mod render;
+pub mod background;
+pub mod pdf;
+#[cfg(feature = "serialize")]
+pub mod scene;
+pub mod worley;
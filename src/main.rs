@@ -1,216 +1,224 @@
-mod types;
-mod obj;
-mod camera;
-mod material;
-mod util;
-mod aabb;
-mod bvh;
-mod texture;
-mod perlin;
-mod aarect;
-
-use std::cell::Cell;
-use std::ptr::slice_from_raw_parts;
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use bevy_math::{Vec3, vec3, Vec4, vec4, Vec4Swizzles};
-use image::Rgba;
-use minifb::{Key, Window, WindowOptions};
-use rand::Rng;
-use crate::aarect::XZRect;
-use crate::bvh::BvhNode;
-use crate::camera::Camera;
-use crate::material::{Dielectric, DiffuseLight, Lambertian, Metal};
-use crate::obj::{HittableList, RotateX, RotateY, RotateZ, Sphere, Translate};
-use crate::texture::{Checker, ImageTexture, MultiplyAdd, SolidColor, Turbulence};
-use crate::types::{Color, Ray};
-
-const RES_360P: (usize, usize) = (640, 360);
-const RES_720P: (usize, usize) = (1280, 720);
-const RES_1080P: (usize, usize) = (1920, 1080);
-const RES_1440P: (usize, usize) = (2560, 1440);
-const RES_4K: (usize, usize) = (3840, 2160);
-const RES_8K: (usize, usize) = (7680, 4320);
-const RES: (usize, usize) = RES_8K;
-const SAMPLES_PER_PIXEL: u32 = 1500;
-const MAX_DEPTH: u32 = 480;
-
-const WIDTH: usize = RES.0;
-const HEIGHT: usize = RES.1;
-
-
-fn to_u32(color: Vec3, samples_per_pixel: u32) -> u32 {
-    let r = color.x;
-    let g = color.y;
-    let b = color.z;
-
-    let scale = 1.0 / (samples_per_pixel as f32);
-    let r = (scale * r).sqrt();
-    let g = (scale * g).sqrt();
-    let b = (scale * b).sqrt();
-
-    let red = (255.999 * r.clamp(0.0, 1.0)) as u8 as u32;
-    let green = (255.999 * g.clamp(0.0, 1.0)) as u8 as u32;
-    let blue = (255.999 * b.clamp(0.0, 1.0)) as u8 as u32;
-    (0xFF << 24) | (red << 16) | (green << 8) | blue
+use std::error::Error;
+use std::f32::consts::PI;
+use std::sync::Arc;
+use bevy_math::{vec3, vec4};
+use image::{Rgba, RgbaImage};
+use minifb::{Window, WindowOptions};
+use rand::SeedableRng;
+use rand::rngs::SmallRng;
+use rtx_mogu::aarect::XZRect;
+use rtx_mogu::background::Background;
+use rtx_mogu::bvh::BvhNode;
+use rtx_mogu::camera::{Camera, CameraKind};
+use rtx_mogu::material::{Dielectric, DiffuseLight, Lambertian, Metal, SharedMaterial};
+use rtx_mogu::obj::{Hittable, HittableList, Sphere, Transform};
+use rtx_mogu::perlin::Perlin;
+use rtx_mogu::render::{render_animation, render_file, render_window, Crop, DebugView, OutputFormat, RenderConfig, ToneMap, TransferFunction, RES_1080P, RES_1440P, RES_360P, RES_4K, RES_720P, RES_8K};
+#[cfg(feature = "serialize")]
+use rtx_mogu::scene::load_scene;
+use rtx_mogu::texture::{AlphaMode, ImageTexture, MultiplyAdd, SolidColor, Turbulence};
+use rtx_mogu::types::Color;
+use rtx_mogu::util::polar_to_xyz;
+
+enum Mode {
+    Window,
+    File(String, OutputFormat),
+    //Output directory and number of frames
+    Animation(String, u32),
 }
 
-fn ray_color(ray: Ray, background: Color, objs: &HittableList, depth: u32) -> Color {
-    if depth == 0 {
-        return Vec4::splat(0.0);
+fn load_image(path: &str) -> Result<RgbaImage, Box<dyn Error>> {
+    match image::open(path) {
+        Ok(image) => Ok(image.to_rgba8()),
+        Err(e) => Err(format!("Failed to load {}: {}", path, e).into()),
     }
-    let hr = match objs.hit(ray, 0.001, f32::INFINITY) {
-        Some(hr) => hr,
-        None => return background,
-    };
-
-    let emitted = hr.material.emitted(hr.u, hr.v, hr.position);
+}
 
-    match hr.material.scatter(&ray, &hr) {
-        None => emitted,
-        Some((attenuation, scattered)) => {
-            emitted + attenuation * ray_color(scattered, background, objs, depth - 1)
-        }
-    }
+fn parse_number<T: std::str::FromStr>(arg: &str, value: String) -> Result<T, Box<dyn Error>> {
+    value.parse().map_err(|_| format!("Invalid value for {}: {}", arg, value).into())
 }
 
-fn render_st(mut window: Window, camera: Camera, objs: HittableList) {
-    let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
-
-    let mut rng = rand::thread_rng();
-    while window.is_open() && !window.is_key_down(Key::Escape) {
-        let start = std::time::Instant::now();
-        for y in 0..HEIGHT {
-            for x in 0..WIDTH {
-                let mut color = Vec3::splat(0.0);
-                for _ in 0..SAMPLES_PER_PIXEL {
-                    let du: f32 = rng.gen();
-                    let dv: f32 = rng.gen();
-                    let u = (x as f32 + du) / (WIDTH - 1) as f32;
-                    let v = 1.0 - (y as f32 + dv) / (HEIGHT - 1) as f32;
-                    let r = camera.ray(u, v);
-                    let c = ray_color(r, Color::new(1.0, 1.0, 1.0, 1.0), &objs, MAX_DEPTH);
-                    color += vec3(c.x, c.y, c.z) * c.w;
+//Mode, camera kind, aperture, background, logo path, scene file and render settings
+type Args = (Mode, CameraKind, f32, Background, String, Option<String>, RenderConfig);
+
+fn parse_args() -> Result<Args, Box<dyn Error>> {
+    let mut mode = "window".to_string();
+    let mut logo = "logo.png".to_string();
+    let mut scene = None;
+    let mut camera = CameraKind::Perspective;
+    let mut aperture = 0.0;
+    let mut background = Background::Solid(Color::new(1.0, 1.0, 1.0, 1.0));
+    let mut output = None;
+    let mut format = OutputFormat::Ldr;
+    let mut frames = 60;
+    let mut config = RenderConfig::default();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("Missing value for {}", arg));
+        match arg.as_str() {
+            "--mode" => mode = value()?,
+            "--output" => output = Some(value()?),
+            "--logo" => logo = value()?,
+            "--scene" => scene = Some(value()?),
+            "--frames" => frames = parse_number(&arg, value()?)?,
+            "--aperture" => aperture = parse_number(&arg, value()?)?,
+            "--format" => {
+                format = match value()?.as_str() {
+                    "png" => OutputFormat::Ldr,
+                    "exr" => OutputFormat::Exr,
+                    format => return Err(format!("Unknown format {}, expected png or exr", format).into()),
                 }
-                buffer[y * WIDTH + x] = to_u32(color, SAMPLES_PER_PIXEL);
             }
-            window
-                .update_with_buffer(&buffer, WIDTH, HEIGHT)
-                .unwrap();
-            if window.is_key_down(Key::Escape) {
-                return;
+            "--camera" => {
+                camera = match value()?.as_str() {
+                    "perspective" => CameraKind::Perspective,
+                    "orthographic" => CameraKind::Orthographic,
+                    "equirect" => CameraKind::Equirect,
+                    kind => return Err(format!("Unknown camera {}, expected perspective, orthographic or equirect", kind).into()),
+                }
             }
+            "--background" => {
+                let value = value()?;
+                background = match value.as_str() {
+                    "white" => Background::Solid(Color::new(1.0, 1.0, 1.0, 1.0)),
+                    "sky" => Background::sky(),
+                    path => Background::environment(load_image(path)?),
+                }
+            }
+            "--resolution" => {
+                (config.width, config.height) = match value()?.as_str() {
+                    "360p" => RES_360P,
+                    "720p" => RES_720P,
+                    "1080p" => RES_1080P,
+                    "1440p" => RES_1440P,
+                    "4k" => RES_4K,
+                    "8k" => RES_8K,
+                    res => return Err(format!("Unknown resolution {}", res).into()),
+                }
+            }
+            "--width" => config.width = parse_number(&arg, value()?)?,
+            "--height" => config.height = parse_number(&arg, value()?)?,
+            "--samples" => config.samples_per_pixel = parse_number(&arg, value()?)?,
+            "--depth" => config.max_depth = parse_number(&arg, value()?)?,
+            "--seed" => config.seed = Some(parse_number(&arg, value()?)?),
+            "--roulette" => config.roulette_min_bounces = Some(parse_number(&arg, value()?)?),
+            "--adaptive" => config.adaptive_threshold = Some(parse_number(&arg, value()?)?),
+            "--debug" => {
+                let value = value()?;
+                //optional parameters follow the view name, separated by ':'
+                let parts: Vec<&str> = value.split(':').collect();
+                let param = |i: usize, default: &str| parts.get(i).copied().unwrap_or(default).to_string();
+                config.debug = match parts[0] {
+                    "normals" => Some(DebugView::Normals),
+                    "depth" => Some(DebugView::Depth {
+                        near: parse_number(&arg, param(1, "0"))?,
+                        far: parse_number(&arg, param(2, "20"))?,
+                    }),
+                    "bvh" => Some(DebugView::BvhVisits { max: parse_number(&arg, param(1, "100"))? }),
+                    view => return Err(format!("Unknown debug view {}, expected normals, depth or bvh", view).into()),
+                }
+            }
+            "--epsilon" => config.ray_epsilon = parse_number(&arg, value()?)?,
+            "--threads" => config.threads = Some(parse_number(&arg, value()?)?),
+            "--stratified" => config.stratified = true,
+            "--stats" => config.stats = true,
+            "--crop" => {
+                let value = value()?;
+                let parts = value.split(',').map(|p| parse_number(&arg, p.to_string())).collect::<Result<Vec<usize>, _>>()?;
+                match parts[..] {
+                    [x0, y0, x1, y1] if x0 < x1 && y0 < y1 => config.crop = Some(Crop { x0, y0, x1, y1 }),
+                    _ => return Err(format!("Invalid value for {}: {}, expected x0,y0,x1,y1", arg, value).into()),
+                }
+            }
+            "--clamp" => config.max_sample_luminance = Some(parse_number(&arg, value()?)?),
+            "--tone-map" => {
+                config.tone_map = match value()?.as_str() {
+                    "gamma" => ToneMap::Gamma,
+                    "reinhard" => ToneMap::Reinhard,
+                    "aces" => ToneMap::ACESFilmic,
+                    tone_map => return Err(format!("Unknown tone map {}, expected gamma, reinhard or aces", tone_map).into()),
+                }
+            }
+            "--exposure" => config.exposure = 2.0f32.powf(parse_number(&arg, value()?)?),
+            "--gamma" => {
+                let value = value()?;
+                config.transfer = match value.as_str() {
+                    "srgb" => TransferFunction::Srgb,
+                    _ => TransferFunction::Gamma(parse_number(&arg, value)?),
+                }
+            }
+            _ => return Err(format!("Unknown argument {}", arg).into()),
         }
-        let elapsed = start.elapsed();
-        println!("Rendered frame in {:?} ({} FPS)", elapsed, 1.0 / elapsed.as_secs_f32());
     }
+    if config.width < 2 || config.height < 2 {
+        return Err("Resolution must be at least 2x2".into());
+    }
+    if config.threads == Some(0) {
+        return Err("Need at least 1 thread".into());
+    }
+
+    let mode = match mode.as_str() {
+        "window" => Mode::Window,
+        "file" => {
+            let output = output.unwrap_or_else(|| match format {
+                OutputFormat::Ldr => "output.png".to_string(),
+                OutputFormat::Exr => "output.exr".to_string(),
+            });
+            Mode::File(output, format)
+        }
+        "animation" => Mode::Animation(output.unwrap_or_else(|| "frames".to_string()), frames),
+        _ => return Err(format!("Unknown mode {}, expected window, file or animation", mode).into()),
+    };
+    Ok((mode, camera, aperture, background, logo, scene, config))
 }
 
-fn render_mt(mut window: Window, camera: Camera, objs: HittableList) {
-    let swap_chain = Arc::new(Mutex::new(Cell::new(vec![0; WIDTH * HEIGHT])));
-    let par = std::thread::available_parallelism().unwrap().get() - 1;
-    let par = par.max(1);
-
-    let stop = Arc::new(AtomicBool::new(false));
-    {
-        let swap_chain = swap_chain.clone();
-        let stop = stop.clone();
-
-        std::thread::spawn(move || {
-            while !stop.load(Ordering::Relaxed) {
-                let start = std::time::Instant::now();
-                let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
-                struct SendPtr(*mut u32);
-                unsafe impl Send for SendPtr {}
-                unsafe impl Sync for SendPtr {}
-                let ptr = SendPtr(buffer.as_mut_ptr());
-
-                let pixel_index = AtomicUsize::new(0);
-                let end_index = WIDTH * HEIGHT;
-                let pixels_per_iter = 64;
-
-                std::thread::scope(|s| {
-                    for _ in 0..par {
-                        s.spawn(|| {
-                            let _ = &ptr;
-
-                            let mut rng = rand::thread_rng();
-                            loop {
-                                let start_idx = pixel_index.fetch_add(pixels_per_iter, Ordering::SeqCst);
-                                if start_idx >= end_index {
-                                    break;
-                                }
-
-                                for idx in start_idx..(start_idx + pixels_per_iter).min(end_index) {
-                                    let x = idx % WIDTH;
-                                    let y = idx / WIDTH;
-
-                                    let mut color = Vec3::splat(0.0);
-                                    for _ in 0..SAMPLES_PER_PIXEL {
-                                        let du: f32 = rng.gen();
-                                        let dv: f32 = rng.gen();
-                                        let u = (x as f32 + du) / (WIDTH - 1) as f32;
-                                        let v = 1.0 - (y as f32 + dv) / (HEIGHT - 1) as f32;
-                                        let r = camera.ray(u, v);
-                                        let c = ray_color(r, Color::new(1.0, 1.0, 1.0, 1.0), &objs, MAX_DEPTH);
-                                        color += vec3(c.x, c.y, c.z) * c.w;
-                                    }
-                                    let res = to_u32(color, SAMPLES_PER_PIXEL);
-                                    unsafe {
-                                        ptr.0.add(idx).write(res);
-                                    }
-                                }
-                            }
-                        });
-                    }
-                });
-                let elapsed = start.elapsed();
-                println!("Rendered frame in {:?} ({} FPS)", elapsed, 1.0 / elapsed.as_secs_f32());
-                let mut copy = buffer.clone();
-                for i in &mut copy {
-                    *i = u32::from_be(i.rotate_left(8));
-                }
-                image::ImageBuffer::<Rgba<u8>, _>::from_raw(WIDTH as _, HEIGHT as _, unsafe {
-                    &*slice_from_raw_parts(copy.as_ptr().cast::<u8>(), copy.len() * 4)
-                }).unwrap().save("output.png").unwrap();
-                swap_chain.lock().unwrap().set(buffer);
-                break;
-            }
-        });
+//Window and file modes, animations need a scene that can change between frames
+fn render_still(mode: Mode, camera: Camera, objs: HittableList, lights: HittableList, background: Background, config: RenderConfig) -> Result<(), Box<dyn Error>> {
+    match mode {
+        Mode::Window => {
+            let window = Window::new(
+                "RTX ON",
+                config.width,
+                config.height,
+                WindowOptions {
+                    resize: true,
+                    ..WindowOptions::default()
+                },
+            )?;
+            render_window(window, camera, objs, lights, background, config);
+        }
+        Mode::File(path, format) => render_file(&path, format, camera, objs, lights, background, config)?,
+        Mode::Animation(..) => return Err("Animations can only be rendered from the built in scene".into()),
     }
+    Ok(())
+}
 
-    window.limit_update_rate(Some(std::time::Duration::from_millis(16)));
-    while window.is_open() && !window.is_key_down(Key::Escape) {
-        let mut lock = swap_chain.lock().unwrap();
-        let buffer = lock.get_mut().clone();
-        drop(lock);
-        window
-            .update_with_buffer(&buffer, WIDTH, HEIGHT)
-            .unwrap();
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
-    stop.store(true, Ordering::Relaxed);
 }
 
-fn main() {
-    let window = Window::new(
-        "RTX ON",
-        WIDTH,
-        HEIGHT,
-        WindowOptions::default(),
-    )
-    .unwrap_or_else(|e| {
-        panic!("{}", e);
-    });
+fn run() -> Result<(), Box<dyn Error>> {
+    let (mode, camera_kind, aperture, background, logo_path, scene, config) = parse_args()?;
 
-    let mut objs = HittableList::new();
+    if let Some(path) = scene {
+        #[cfg(not(feature = "serialize"))]
+        return Err(format!("Can't render {}, scene files need the serialize feature (build with --features serialize)", path).into());
 
-    let polar_to_xyz = |radius: f32, phi: f32, theta: f32| {
-        vec3(
-            radius * phi.sin() * theta.cos(),
-            radius * phi.cos(),
-            radius * phi.sin() * theta.sin(),
-        )
-    };
+        #[cfg(feature = "serialize")]
+        {
+            let scene = load_scene(&path, config.width as f32 / config.height as f32)?;
+            //the file's background wins over --background
+            let background = scene.background.unwrap_or(background);
+            return render_still(mode, scene.camera.with_kind(camera_kind), scene.objs, scene.lights, background, config);
+        }
+    }
+
+    let mut scene_rng = SmallRng::seed_from_u64(config.seed.unwrap_or_else(rand::random));
+    let noise = Perlin::new(&mut scene_rng);
 
     let make_mogu = |radius: f32| {
         let mut mogu = HittableList::new();
@@ -219,19 +227,19 @@ fn main() {
         //rgb(164, 255, 82)
         let color = vec4(164.0/255.0, 255.0/255.0, 82.0/255.0, 1.0);
 
-        let point = |phi: f32, theta: f32, r: f32, color: Color| {
+        let point = |phi: f32, theta: f32, r: f32, material: &SharedMaterial| {
             let x = polar_to_xyz(radius, phi, theta);
-            Sphere::new(x, r, Lambertian::new(SolidColor::new(color)))
+            Sphere::new(x, r, material.clone())
         };
 
         mogu.add(Sphere::new(vec3(0.0, 0.0, 0.0), radius,
              Dielectric::new(MultiplyAdd::new(
                  SolidColor::new(color),
                  SolidColor::new(Color::splat(0.5)),
-                 Turbulence::new(SolidColor::new(color), 20.0)
+                 Turbulence::from_perlin(SolidColor::new(color), 20.0, noise.clone())
              ), 100.0)
         ));
-        let eye1 = |rotation_start: f32, rotation_end: f32, base_phi: f32, direction: f32, color: Color| {
+        let eye1 = |rotation_start: f32, rotation_end: f32, base_phi: f32, direction: f32, material: &SharedMaterial| {
             let rotation_start = rotation_start.to_radians();
             let rotation_end = rotation_end.to_radians();
 
@@ -251,11 +259,11 @@ fn main() {
                 let y = a * i_scale * i_scale + b * i_scale + c;
                 let y = y * 0.5 + base_phi;
                 let radius = min_radius + (max_radius - min_radius) * (1.0 - i_scale);
-                spheres.add(point(y, x, radius, color));
+                spheres.add(point(y, x, radius, material));
             }
             BvhNode::new(&spheres.into_vec()[..])
         };
-        let eye2 = |rotation_start: f32, rotation_end: f32, base_phi: f32, direction: f32, color: Color| {
+        let eye2 = |rotation_start: f32, rotation_end: f32, base_phi: f32, direction: f32, material: &SharedMaterial| {
             let rotation_start = rotation_start.to_radians();
             let rotation_end = rotation_end.to_radians();
 
@@ -275,25 +283,26 @@ fn main() {
                 let y = a * i_scale * i_scale + b * i_scale + c;
                 let y = y * 0.5 + base_phi;
                 let radius = min_radius + (max_radius - min_radius) * i_scale;
-                spheres.add(point(y, x, radius, color));
+                spheres.add(point(y, x, radius, material));
             }
             BvhNode::new(&spheres.into_vec()[..])
         };
 
-        let black = vec4(0.0, 0.0, 0.0, 1.0);
+        //the eyes and mouth are made of hundreds of spheres, all sharing this
+        let black: SharedMaterial = Arc::new(Lambertian::new(SolidColor::new(vec4(0.0, 0.0, 0.0, 1.0))));
 
         let base_phi = 70.0f32.to_radians();
         let base = 60.0;
         let width = 20.0;
         let gap = 40.0;
         let v_dist = 5.0f32.to_radians();
-        mogu.add(eye1(base, base + width, base_phi, 1.0, black));
-        mogu.add(eye1(base + width + gap, base + width + gap + width, base_phi, -1.0, black));
+        mogu.add(eye1(base, base + width, base_phi, 1.0, &black));
+        mogu.add(eye1(base + width + gap, base + width + gap + width, base_phi, -1.0, &black));
 
-        mogu.add(eye2(base + width * 5.0 / 6.0, base + width, base_phi - v_dist, 1.0, black));
-        mogu.add(eye2(base + width + gap / 2.0 + width / 6.0, base + width + gap / 2.0 + width / 6.0 * 2.0, base_phi - v_dist, -1.0, black));
+        mogu.add(eye2(base + width * 5.0 / 6.0, base + width, base_phi - v_dist, 1.0, &black));
+        mogu.add(eye2(base + width + gap / 2.0 + width / 6.0, base + width + gap / 2.0 + width / 6.0 * 2.0, base_phi - v_dist, -1.0, &black));
 
-        let mouth = |start_theta: f32, width: f32, start_phi: f32, turn_radius: f32, color: Color| {
+        let mouth = |start_theta: f32, width: f32, start_phi: f32, turn_radius: f32, material: &SharedMaterial| {
             let mut spheres = HittableList::new();
 
             let start_theta = start_theta.to_radians();
@@ -311,7 +320,7 @@ fn main() {
                 let y = start_phi + (r2 - (turn_radius*(i_scale * 2.0 - 1.0)).powf(2.0)).sqrt();
 
 
-                spheres.add(point(y, x, radius, color));
+                spheres.add(point(y, x, radius, material));
             }
 
             BvhNode::new(&spheres.into_vec()[..])
@@ -320,8 +329,8 @@ fn main() {
         let center = 90.0;
         let radius = 0.1;
         let y = 90.0f32.to_radians();
-        mogu.add(mouth(center - width, width, y, radius, black));
-        mogu.add(mouth(center, width, y, radius, black));
+        mogu.add(mouth(center - width, width, y, radius, &black));
+        mogu.add(mouth(center, width, y, radius, &black));
 
 
         BvhNode::new(&mogu.into_vec()[..])
@@ -329,12 +338,13 @@ fn main() {
     let mogu_center = vec3(-1.0, 0.0, -1.4);
     let mogu_radius = 1.2;
 
-    let mogu = make_mogu(mogu_radius);
-    let mogu = RotateX::new(mogu, (-60.0f32).to_radians());
-    let mogu = Translate::new(mogu, mogu_center);
-    objs.add(mogu);
+    let mogu = Arc::new(make_mogu(mogu_radius));
 
-    let mut logo = image::open("logo.png").unwrap().to_rgba8().to_owned();
+    let mut logo = load_image(&logo_path).unwrap_or_else(|e| {
+        eprintln!("{}, using a plain logo instead", e);
+        //black turns into gold below
+        RgbaImage::from_pixel(1, 1, Rgba([0, 0, 0, 255]))
+    });
     for p in logo.pixels_mut() {
         let arr = &mut p.0;
         //invert image from black to white
@@ -350,37 +360,63 @@ fn main() {
     let logo = image::imageops::rotate180(&logo);
 
     let logo_size = 0.5;
-    let logo = XZRect::new(-logo_size/2.0, logo_size/2.0, -logo_size/2.0, logo_size/2.0, 0.0,
-        Metal::new(ImageTexture::new(logo), 1.5),
-    );
-    let logo = RotateZ::new(logo, -35.0f32.to_radians());
-    let logo = Translate::new(logo, mogu_center + polar_to_xyz(
-        mogu_radius,
-        35.0f32.to_radians(),
-        0.0f32.to_radians())
-    );
-    objs.add(logo);
-
-    objs.add(Sphere::new(vec3(20.0, 15.0, -20.0), 6.0,
-        DiffuseLight::color(vec4(1.0, 1.0, 0.5, 8.0))
+    let logo = Arc::new(XZRect::new(-logo_size/2.0, logo_size/2.0, -logo_size/2.0, logo_size/2.0, 0.0,
+        Metal::new(ImageTexture::new(logo).with_alpha_mode(AlphaMode::Blend), 1.0),
+    ));
+
+    let sun: Arc<dyn Hittable + Send + Sync> = Arc::new(Sphere::new(vec3(20.0, 15.0, -20.0), 6.0,
+        DiffuseLight::with_intensity(SolidColor::new(vec4(1.0, 1.0, 0.5, 1.0)), 8.0)
     ));
 
+    //The mogu and its logo are turned `spin` radians around the vertical axis, for animations
+    let build_objs = |spin: f32| {
+        let mut objs = HittableList::new();
+        objs.add(Transform::new(mogu.clone())
+            .rotate_x((-60.0f32).to_radians())
+            .rotate_y(spin)
+            .translate(mogu_center)
+        );
+        objs.add(Transform::new(logo.clone())
+            .rotate_z(-35.0f32.to_radians())
+            .translate(polar_to_xyz(
+                mogu_radius,
+                35.0f32.to_radians(),
+                0.0f32.to_radians())
+            )
+            .rotate_y(spin)
+            .translate(mogu_center)
+        );
+        //Lights are also sampled directly, so they need to be in both lists
+        objs.add_shared(sun.clone());
+        let mut lights = HittableList::new();
+        lights.add_shared(sun.clone());
+        (objs, lights)
+    };
+
     let look_from = vec3(-1.0, 8.0, 3.0);
     let look_at = vec3(-1.0, 0.5, -1.0);
 
     let vup = vec3(0.0, 1.0, 0.0);
-    let dist_to_focus = 10.0;
-    let aperture = 0.0;
 
-    let camera = Camera::new(
+    let camera = Camera::with_autofocus(
         look_from,
         look_at,
         vup,
         20.0,
-        WIDTH as f32 / HEIGHT as f32,
+        config.width as f32 / config.height as f32,
         aperture,
-        dist_to_focus,
-    );
-
-    render_mt(window, camera, objs);
+    ).with_kind(camera_kind);
+
+    match mode {
+        Mode::Animation(directory, frames) => render_animation(&directory, frames, background, config, |t| {
+            //one full turn over the animation
+            let (objs, lights) = build_objs(t * 2.0 * PI);
+            (camera, objs, lights)
+        })?,
+        mode => {
+            let (objs, lights) = build_objs(0.0);
+            render_still(mode, camera, objs, lights, background, config)?;
+        }
+    }
+    Ok(())
 }
@@ -1,31 +1,125 @@
-use bevy_math::Vec4;
+use std::f32::consts::PI;
+use std::sync::Arc;
+use bevy_math::{Vec3, Vec4, Vec4Swizzles};
 use rand::Rng;
+use rand::rngs::SmallRng;
 use crate::obj::HitResult;
+use crate::pdf::{CosinePdf, ggx_d, GgxPdf, MixturePdf, Pdf};
 use crate::texture::{SolidColor, Texture};
-use crate::types::{Color, Point3, Ray};
-use crate::util;
-use crate::util::{near_zero, random_in_unit_sphere, reflect, reflectance, refract, unit_vector};
+use crate::types::{Color, linear, Point3, Ray, scale};
+use crate::util::{fresnel, Onb, near_zero, random_in_unit_sphere, random_unit_vector, reflect, reflectance, refract, unit_vector};
+
+pub enum ScatterKind {
+    //A single direction picked by the material, with the attenuation accounting for everything else.
+    //Lights aren't sampled directly from these bounces
+    Specular(Ray),
+    //Directions are drawn from `pdf`, and the attenuation times its density is the BSDF times the cosine,
+    //which lets the tracer also send rays towards lights and weight both
+    Diffuse { pdf: Box<dyn Pdf> },
+    //Directions are drawn from `pdf` too, for materials whose reflectance changes with the direction.
+    //`bsdf` gives the BSDF times the cosine towards any direction, which the attenuation is multiplied by
+    Lobe { pdf: Box<dyn Pdf>, bsdf: Box<dyn Fn(Vec3) -> Vec3> },
+}
+
+pub struct ScatterRecord {
+    pub attenuation: Color,
+    pub kind: ScatterKind,
+}
+
+impl ScatterRecord {
+    pub fn specular(attenuation: Color, ray: Ray) -> Self {
+        Self {
+            attenuation,
+            kind: ScatterKind::Specular(ray),
+        }
+    }
+}
 
 pub trait Material {
     //Hack to implement transparency for textures
     fn hack_solid(&self, _: f32, _: f32, _: Point3) -> bool;
 
-    fn emitted(&self, _: f32, _: f32, _: Point3) -> Color {
+    //Coverage of solid hits, rays go through with probability 1 - alpha
+    fn alpha(&self, _: f32, _: f32, _: Point3) -> f32 {
+        1.0
+    }
+
+    fn emitted(&self, _: &HitResult) -> Color {
         Vec4::splat(0.0)
     }
 
-    fn scatter(&self, ray: &Ray, hit: &HitResult) -> Option<(Color, Ray)>;
+    fn scatter(&self, ray: &Ray, hit: &HitResult, rng: &mut SmallRng) -> Option<ScatterRecord>;
+
+    //Fraction of light absorbed per unit of distance traveled inside objects made of this material
+    fn absorption(&self) -> Vec3 {
+        Vec3::ZERO
+    }
+}
+
+//Lets many objects share one material instead of each holding its own copy
+pub type SharedMaterial = Arc<dyn Material + Send + Sync>;
+
+impl<M: Material + ?Sized> Material for Arc<M> {
+    fn hack_solid(&self, u: f32, v: f32, p: Point3) -> bool {
+        (**self).hack_solid(u, v, p)
+    }
+
+    fn alpha(&self, u: f32, v: f32, p: Point3) -> f32 {
+        (**self).alpha(u, v, p)
+    }
+
+    fn emitted(&self, hit: &HitResult) -> Color {
+        (**self).emitted(hit)
+    }
+
+    fn scatter(&self, ray: &Ray, hit: &HitResult, rng: &mut SmallRng) -> Option<ScatterRecord> {
+        (**self).scatter(ray, hit, rng)
+    }
+
+    fn absorption(&self) -> Vec3 {
+        (**self).absorption()
+    }
+}
+
+//Decodes a tangent space normal (2 * rgb - 1) from the normal map and moves it into world space.
+//HitResult has no tangents, so the tangent frame is derived from the geometric normal alone
+fn shading_normal<N: Texture>(normal_map: &Option<N>, hit: &HitResult) -> Vec3 {
+    let map = match normal_map {
+        Some(map) => map,
+        None => return hit.normal,
+    };
+    let texel = map.value_at(hit).xyz();
+    let local = 2.0 * texel - Vec3::ONE;
+    let normal = unit_vector(Onb::from_w(hit.normal).local(local));
+    //maps pointing below the surface would make rays go through it
+    if near_zero(normal) || normal.dot(hit.normal) <= 0.0 {
+        hit.normal
+    } else {
+        normal
+    }
 }
 
 #[derive(Copy, Clone)]
-pub struct Lambertian<T: Texture> {
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct Lambertian<T: Texture, N: Texture = SolidColor> {
     albedo: T,
+    normal_map: Option<N>,
 }
 
 impl<T: Texture> Lambertian<T> {
     pub fn new(albedo: T) -> Self {
         Self {
             albedo,
+            normal_map: None,
+        }
+    }
+}
+
+impl<T: Texture, N: Texture> Lambertian<T, N> {
+    pub fn with_normal_map<M: Texture>(self, normal_map: M) -> Lambertian<T, M> {
+        Lambertian {
+            albedo: self.albedo,
+            normal_map: Some(normal_map),
         }
     }
 }
@@ -36,31 +130,120 @@ impl Lambertian<SolidColor> {
     }
 }
 
-impl<T: Texture> Material for Lambertian<T> {
+impl<T: Texture, N: Texture> Material for Lambertian<T, N> {
     fn hack_solid(&self, u: f32, v: f32, p: Point3) -> bool {
         self.albedo.hack_solid(u, v, p)
     }
 
-    fn scatter(&self, _: &Ray, hit: &HitResult) -> Option<(Color, Ray)> {
-        let mut scatter_direction = hit.normal + util::random_unit_vector();
-        if near_zero(scatter_direction) {
-            scatter_direction = hit.normal;
+    fn alpha(&self, u: f32, v: f32, p: Point3) -> f32 {
+        self.albedo.alpha(u, v, p)
+    }
+
+    fn scatter(&self, _: &Ray, hit: &HitResult, _: &mut SmallRng) -> Option<ScatterRecord> {
+        let normal = shading_normal(&self.normal_map, hit);
+        Some(ScatterRecord {
+            attenuation: self.albedo.value_at(hit),
+            kind: ScatterKind::Diffuse { pdf: Box::new(CosinePdf::new(normal)) },
+        })
+    }
+}
+
+//Rough diffuse surface (clay, stone), roughness is the standard deviation of the microfacet
+//angles in radians. Roughness 0 is the same as Lambertian
+#[derive(Copy, Clone)]
+pub struct OrenNayar<T: Texture> {
+    albedo: T,
+    a: f32,
+    b: f32,
+}
+
+impl<T: Texture> OrenNayar<T> {
+    pub fn new(albedo: T, roughness: f32) -> Self {
+        let s2 = roughness * roughness;
+        Self {
+            albedo,
+            a: 1.0 - 0.5 * s2 / (s2 + 0.33),
+            b: 0.45 * s2 / (s2 + 0.09),
         }
-        Some((self.albedo.value(hit.u, hit.v, hit.position), Ray::new(hit.position, scatter_direction)))
+    }
+}
+
+impl OrenNayar<SolidColor> {
+    pub fn color(albedo: Color, roughness: f32) -> Self {
+        Self::new(SolidColor::new(albedo), roughness)
+    }
+}
+
+impl<T: Texture> Material for OrenNayar<T> {
+    fn hack_solid(&self, u: f32, v: f32, p: Point3) -> bool {
+        self.albedo.hack_solid(u, v, p)
+    }
+
+    fn alpha(&self, u: f32, v: f32, p: Point3) -> f32 {
+        self.albedo.alpha(u, v, p)
+    }
+
+    //Cosine weighted like Lambertian, the lobe adds the Oren-Nayar factor
+    fn scatter(&self, ray: &Ray, hit: &HitResult, _: &mut SmallRng) -> Option<ScatterRecord> {
+        let (a, b) = (self.a, self.b);
+        let n = hit.normal;
+        let wi = -unit_vector(ray.direction);
+        let cos_i = n.dot(wi).clamp(-1.0, 1.0);
+        let theta_i = cos_i.acos();
+        //projected onto the surface, for the azimuth difference
+        let pi = wi - n * cos_i;
+
+        let bsdf = move |direction: Vec3| {
+            let wo = unit_vector(direction);
+            let cos_o = n.dot(wo).clamp(-1.0, 1.0);
+            if cos_o <= 0.0 {
+                return Vec3::ZERO;
+            }
+            let theta_o = cos_o.acos();
+            let alpha = theta_i.max(theta_o);
+            let beta = theta_i.min(theta_o);
+
+            let po = wo - n * cos_o;
+            let cos_phi = if near_zero(pi) || near_zero(po) {
+                0.0
+            } else {
+                unit_vector(pi).dot(unit_vector(po)).max(0.0)
+            };
+
+            Vec3::splat((a + b * cos_phi * alpha.sin() * beta.tan()) * cos_o / PI)
+        };
+        Some(ScatterRecord {
+            attenuation: self.albedo.value_at(hit),
+            kind: ScatterKind::Lobe { pdf: Box::new(CosinePdf::new(n)), bsdf: Box::new(bsdf) },
+        })
     }
 }
 
 #[derive(Copy, Clone)]
-pub struct Metal<T: Texture> {
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct Metal<T: Texture, N: Texture = SolidColor> {
     albedo: T,
     fuzz: f32,
+    normal_map: Option<N>,
 }
 
 impl<T: Texture> Metal<T> {
+    //Above 1 the fuzz sends most reflections below the surface, where they are absorbed
     pub fn new(albedo: T, fuzz: f32) -> Self {
         Self {
             albedo,
-            fuzz,
+            fuzz: fuzz.clamp(0.0, 1.0),
+            normal_map: None,
+        }
+    }
+}
+
+impl<T: Texture, N: Texture> Metal<T, N> {
+    pub fn with_normal_map<M: Texture>(self, normal_map: M) -> Metal<T, M> {
+        Metal {
+            albedo: self.albedo,
+            fuzz: self.fuzz,
+            normal_map: Some(normal_map),
         }
     }
 }
@@ -71,26 +254,177 @@ impl Metal<SolidColor> {
     }
 }
 
-impl<T: Texture> Material for Metal<T> {
+impl<T: Texture, N: Texture> Material for Metal<T, N> {
+    fn hack_solid(&self, u: f32, v: f32, p: Point3) -> bool {
+        self.albedo.hack_solid(u, v, p)
+    }
+
+    fn alpha(&self, u: f32, v: f32, p: Point3) -> f32 {
+        self.albedo.alpha(u, v, p)
+    }
+
+    fn scatter(&self, ray: &Ray, hit: &HitResult, rng: &mut SmallRng) -> Option<ScatterRecord> {
+        let reflected = reflect(unit_vector(ray.direction), shading_normal(&self.normal_map, hit));
+        let scattered = Ray::new(hit.position, reflected + self.fuzz * random_in_unit_sphere(rng), ray.time);
+        if scattered.direction.dot(hit.normal) > 0.0 {
+            Some(ScatterRecord::specular(self.albedo.value_at(hit), scattered))
+        } else {
+            None
+        }
+    }
+}
+
+//Brushed metal, fuzz is stretched along a tangent frame derived from the normal.
+//Equal roughnesses behave like Metal with that fuzz, and are clamped to [0, 1] the same way
+#[derive(Copy, Clone)]
+pub struct AnisotropicMetal<T: Texture> {
+    albedo: T,
+    roughness_u: f32,
+    roughness_v: f32,
+}
+
+impl<T: Texture> AnisotropicMetal<T> {
+    pub fn new(albedo: T, roughness_u: f32, roughness_v: f32) -> Self {
+        Self {
+            albedo,
+            roughness_u: roughness_u.clamp(0.0, 1.0),
+            roughness_v: roughness_v.clamp(0.0, 1.0),
+        }
+    }
+}
+
+impl AnisotropicMetal<SolidColor> {
+    pub fn color(albedo: Color, roughness_u: f32, roughness_v: f32) -> Self {
+        Self::new(SolidColor::new(albedo), roughness_u, roughness_v)
+    }
+}
+
+impl<T: Texture> Material for AnisotropicMetal<T> {
     fn hack_solid(&self, u: f32, v: f32, p: Point3) -> bool {
         self.albedo.hack_solid(u, v, p)
     }
 
-    fn scatter(&self, ray: &Ray, hit: &HitResult) -> Option<(Color, Ray)> {
+    fn alpha(&self, u: f32, v: f32, p: Point3) -> f32 {
+        self.albedo.alpha(u, v, p)
+    }
+
+    fn scatter(&self, ray: &Ray, hit: &HitResult, rng: &mut SmallRng) -> Option<ScatterRecord> {
         let reflected = reflect(unit_vector(ray.direction), hit.normal);
-        let scattered = Ray::new(hit.position, reflected + self.fuzz * random_in_unit_sphere());
+        let frame = Onb::from_w(hit.normal);
+        let p = random_in_unit_sphere(rng);
+        let fuzz = self.roughness_u * p.dot(frame.u) * frame.u
+            + self.roughness_v * p.dot(frame.v) * frame.v
+            + 0.5 * (self.roughness_u + self.roughness_v) * p.dot(frame.w) * frame.w;
+        let scattered = Ray::new(hit.position, reflected + fuzz, ray.time);
         if scattered.direction.dot(hit.normal) > 0.0 {
-            Some((self.albedo.value(hit.u, hit.v, hit.position), scattered))
+            Some(ScatterRecord::specular(self.albedo.value_at(hit), scattered))
         } else {
             None
         }
     }
 }
 
+//Metallic-roughness material: GGX microfacet specular with Schlick's Fresnel over a Lambertian base.
+//Metallic 1 is a pure (colored) specular lobe, roughness 0 is a mirror
+#[derive(Copy, Clone)]
+pub struct Pbr<T: Texture> {
+    base_color: T,
+    metallic: f32,
+    roughness: f32,
+}
+
+impl<T: Texture> Pbr<T> {
+    pub fn new(base_color: T, metallic: f32, roughness: f32) -> Self {
+        Self {
+            base_color,
+            metallic: metallic.clamp(0.0, 1.0),
+            roughness: roughness.clamp(0.0, 1.0),
+        }
+    }
+
+    //Probability of sampling the specular lobe instead of the diffuse one
+    fn specular_probability(&self) -> f32 {
+        0.5 + 0.5 * self.metallic
+    }
+}
+
+impl Pbr<SolidColor> {
+    pub fn color(base_color: Color, metallic: f32, roughness: f32) -> Self {
+        Self::new(SolidColor::new(base_color), metallic, roughness)
+    }
+}
+
+//Smith masking for a single direction
+fn ggx_g1(n_dot_v: f32, alpha2: f32) -> f32 {
+    2.0 * n_dot_v / (n_dot_v + (alpha2 + (1.0 - alpha2) * n_dot_v * n_dot_v).sqrt())
+}
+
+impl<T: Texture> Material for Pbr<T> {
+    fn hack_solid(&self, u: f32, v: f32, p: Point3) -> bool {
+        self.base_color.hack_solid(u, v, p)
+    }
+
+    fn alpha(&self, u: f32, v: f32, p: Point3) -> f32 {
+        self.base_color.alpha(u, v, p)
+    }
+
+    //Samples one of the lobes, but the BSDF covers both so light samples are weighted correctly
+    fn scatter(&self, ray: &Ray, hit: &HitResult, _: &mut SmallRng) -> Option<ScatterRecord> {
+        let n = hit.normal;
+        let wi = -unit_vector(ray.direction);
+        let n_dot_i = n.dot(wi);
+        if n_dot_i <= 0.0 {
+            return None;
+        }
+        //a perfect mirror has an infinitely thin distribution, keep it representable
+        let alpha = (self.roughness * self.roughness).max(1e-3);
+        let alpha2 = alpha * alpha;
+        let metallic = self.metallic;
+
+        let base = self.base_color.value_at(hit);
+        let f0 = Vec3::splat(0.04).lerp(base.xyz(), metallic);
+        let bsdf = move |direction: Vec3| {
+            let wo = unit_vector(direction);
+            let n_dot_o = n.dot(wo);
+            if n_dot_o <= 0.0 {
+                return Vec3::ZERO;
+            }
+
+            let h = unit_vector(wi + wo);
+            let n_dot_h = n.dot(h).max(0.0);
+            let o_dot_h = wo.dot(h).max(1e-6);
+            let fresnel = f0 + (Vec3::ONE - f0) * (1.0 - o_dot_h).powi(5);
+
+            let d = ggx_d(n_dot_h, alpha2);
+            let g = ggx_g1(n_dot_i, alpha2) * ggx_g1(n_dot_o, alpha2);
+            let specular = fresnel * (d * g / (4.0 * n_dot_i * n_dot_o));
+            let diffuse = (Vec3::ONE - fresnel) * (1.0 - metallic) * base.xyz() / PI;
+            (specular + diffuse) * n_dot_o
+        };
+
+        let pdf = MixturePdf::weighted(GgxPdf::new(n, wi, alpha2), CosinePdf::new(n), self.specular_probability());
+        Some(ScatterRecord {
+            attenuation: Vec3::ONE.extend(base.w),
+            kind: ScatterKind::Lobe { pdf: Box::new(pdf), bsdf: Box::new(bsdf) },
+        })
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub enum FresnelModel {
+    Schlick,
+    //Full Fresnel equations, slower but more accurate at grazing angles
+    Exact,
+}
+
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub struct Dielectric<T: Texture> {
     texture: T,
     ir: f32,
+    fresnel: FresnelModel,
+    absorption: Color,
 }
 
 impl<T: Texture> Dielectric<T> {
@@ -98,8 +432,20 @@ impl<T: Texture> Dielectric<T> {
         Self {
             texture,
             ir: index_of_refraction,
+            fresnel: FresnelModel::Schlick,
+            absorption: Vec4::splat(0.0),
         }
     }
+
+    pub fn with_fresnel(mut self, fresnel: FresnelModel) -> Self {
+        self.fresnel = fresnel;
+        self
+    }
+
+    pub fn with_absorption(mut self, absorption: Color) -> Self {
+        self.absorption = absorption;
+        self
+    }
 }
 
 impl<T: Texture> Material for Dielectric<T> {
@@ -107,7 +453,11 @@ impl<T: Texture> Material for Dielectric<T> {
         self.texture.hack_solid(u, v, p)
     }
 
-    fn scatter(&self, ray: &Ray, hit: &HitResult) -> Option<(Color, Ray)> {
+    fn alpha(&self, u: f32, v: f32, p: Point3) -> f32 {
+        self.texture.alpha(u, v, p)
+    }
+
+    fn scatter(&self, ray: &Ray, hit: &HitResult, rng: &mut SmallRng) -> Option<ScatterRecord> {
         let refraction_ratio = if hit.front_face {
             1.0 / self.ir
         } else {
@@ -119,27 +469,51 @@ impl<T: Texture> Material for Dielectric<T> {
         let cos_theta = (-unit_dir).dot(hit.normal).min(1.0);
         let sin_theta = (1.0 - cos_theta*cos_theta).sqrt();
 
-        let direction = if refraction_ratio * sin_theta > 1.0 || reflectance(cos_theta, refraction_ratio) > rand::thread_rng().gen::<f32>() {
+        let reflectance = match self.fresnel {
+            FresnelModel::Schlick => reflectance(cos_theta, refraction_ratio),
+            FresnelModel::Exact => fresnel(cos_theta, refraction_ratio),
+        };
+        let direction = if refraction_ratio * sin_theta > 1.0 || reflectance > rng.gen::<f32>() {
             reflect(unit_dir, hit.normal)
         } else {
             refract(unit_dir, hit.normal, refraction_ratio)
         };
 
-        Some((self.texture.value(hit.u, hit.v, hit.position), Ray::new(hit.position, direction)))
+        Some(ScatterRecord::specular(self.texture.value_at(hit), Ray::new(hit.position, direction, ray.time)))
+    }
+
+    fn absorption(&self) -> Vec3 {
+        linear(self.absorption)
     }
 }
 
+//Emits texture color * intensity, from both sides of the surface unless made one sided
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub struct DiffuseLight<T: Texture> {
     texture: T,
+    intensity: f32,
+    two_sided: bool,
 }
 
 impl<T: Texture> DiffuseLight<T> {
     pub fn new(texture: T) -> Self {
+        Self::with_intensity(texture, 1.0)
+    }
+
+    pub fn with_intensity(texture: T, intensity: f32) -> Self {
         Self {
             texture,
+            intensity,
+            two_sided: true,
         }
     }
+
+    //One sided lights only emit from the side their normal points to, e.g. the outside of a sphere
+    pub fn with_two_sided(mut self, two_sided: bool) -> Self {
+        self.two_sided = two_sided;
+        self
+    }
 }
 
 impl DiffuseLight<SolidColor> {
@@ -149,15 +523,201 @@ impl DiffuseLight<SolidColor> {
 }
 
 impl<T: Texture> Material for DiffuseLight<T> {
-    fn hack_solid(&self, _: f32, _: f32, _: Point3) -> bool {
-        true
+    //transparent parts of an image don't emit, rays go through them
+    fn hack_solid(&self, u: f32, v: f32, p: Point3) -> bool {
+        self.texture.hack_solid(u, v, p)
+    }
+
+    fn alpha(&self, u: f32, v: f32, p: Point3) -> f32 {
+        self.texture.alpha(u, v, p)
     }
 
-    fn emitted(&self, u: f32, v: f32, p: Point3) -> Color {
-        self.texture.value(u, v, p)
+    fn emitted(&self, hit: &HitResult) -> Color {
+        if !self.two_sided && !hit.front_face {
+            return Vec4::splat(0.0);
+        }
+        scale(self.texture.value_at(hit), self.intensity)
     }
 
-    fn scatter(&self, _: &Ray, _: &HitResult) -> Option<(Color, Ray)> {
+    fn scatter(&self, _: &Ray, _: &HitResult, _: &mut SmallRng) -> Option<ScatterRecord> {
         None
     }
 }
+
+#[derive(Copy, Clone)]
+pub struct Isotropic<T: Texture> {
+    albedo: T,
+}
+
+impl<T: Texture> Isotropic<T> {
+    pub fn new(albedo: T) -> Self {
+        Self {
+            albedo,
+        }
+    }
+}
+
+impl Isotropic<SolidColor> {
+    pub fn color(albedo: Color) -> Self {
+        Self::new(SolidColor::new(albedo))
+    }
+}
+
+impl<T: Texture> Material for Isotropic<T> {
+    fn hack_solid(&self, u: f32, v: f32, p: Point3) -> bool {
+        self.albedo.hack_solid(u, v, p)
+    }
+
+    fn alpha(&self, u: f32, v: f32, p: Point3) -> f32 {
+        self.albedo.alpha(u, v, p)
+    }
+
+    //Scatters uniformly in every direction, regardless of the incoming ray
+    fn scatter(&self, ray: &Ray, hit: &HitResult, rng: &mut SmallRng) -> Option<ScatterRecord> {
+        Some(ScatterRecord::specular(self.albedo.value_at(hit), Ray::new(hit.position, random_unit_vector(rng), ray.time)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_math::{vec3, vec4};
+    use image::{Rgba, RgbaImage};
+    use rand::SeedableRng;
+    use crate::aarect::XZRect;
+    use crate::obj::Hittable;
+    use crate::texture::ImageTexture;
+    use crate::util::random_unit_vector;
+    use super::*;
+
+    //Hit on the xz plane at the origin, facing up
+    fn hit(material: &dyn Material) -> HitResult<'_> {
+        HitResult {
+            position: Vec3::ZERO,
+            local: Vec3::ZERO,
+            normal: Vec3::Y,
+            t: 1.0,
+            front_face: true,
+            material,
+            u: 0.5,
+            v: 0.5,
+        }
+    }
+
+    fn lobe(record: &ScatterRecord) -> (&dyn Pdf, &dyn Fn(Vec3) -> Vec3) {
+        match &record.kind {
+            ScatterKind::Lobe { pdf, bsdf } => (pdf.as_ref(), bsdf.as_ref()),
+            _ => panic!("Expected a lobe"),
+        }
+    }
+
+    #[test]
+    fn smooth_oren_nayar_is_lambertian() {
+        let material = OrenNayar::color(vec4(0.5, 0.5, 0.5, 1.0), 0.0);
+        let mut rng = SmallRng::seed_from_u64(1);
+        let ray = Ray::new(vec3(1.0, 1.0, 0.0), vec3(-1.0, -1.0, 0.0), 0.0);
+        let record = material.scatter(&ray, &hit(&material), &mut rng).unwrap();
+        let (pdf, bsdf) = lobe(&record);
+        for _ in 0..100 {
+            let direction = random_unit_vector(&mut rng);
+            let expected = direction.dot(Vec3::Y).max(0.0) / PI;
+            assert!((bsdf(direction) - Vec3::splat(expected)).abs().max_element() < 1e-5);
+            //sampled proportionally to the cosine, like Lambertian
+            assert!((pdf.value(direction, &mut rng) - expected).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn isotropic_anisotropic_metal_matches_metal() {
+        let albedo = vec4(0.8, 0.6, 0.2, 1.0);
+        let metal = Metal::color(albedo, 0.3);
+        let anisotropic = AnisotropicMetal::color(albedo, 0.3, 0.3);
+        let ray = Ray::new(vec3(-1.0, 1.0, 0.3), vec3(1.0, -1.0, -0.3), 0.0);
+        for seed in 0..1000 {
+            let a = metal.scatter(&ray, &hit(&metal), &mut SmallRng::seed_from_u64(seed));
+            let b = anisotropic.scatter(&ray, &hit(&anisotropic), &mut SmallRng::seed_from_u64(seed));
+            match (a, b) {
+                (Some(a), Some(b)) => {
+                    assert_eq!(a.attenuation, b.attenuation);
+                    let (ScatterKind::Specular(a), ScatterKind::Specular(b)) = (a.kind, b.kind) else {
+                        panic!("Expected specular bounces");
+                    };
+                    assert!((a.direction - b.direction).abs().max_element() < 1e-5);
+                }
+                (None, None) => {}
+                _ => panic!("Only one of them absorbed the ray"),
+            }
+        }
+
+        //fuzz above 1 is clamped like Metal's
+        let rough = AnisotropicMetal::color(albedo, 5.0, -1.0);
+        assert_eq!((rough.roughness_u, rough.roughness_v), (1.0, 0.0));
+    }
+
+    #[test]
+    fn fuzzy_metal_rarely_absorbs() {
+        let rejected = |metal: &Metal<SolidColor>| {
+            //45 degrees, with fuzz 1 about 6% of the reflections end up below the surface
+            let ray = Ray::new(vec3(-1.0, 1.0, 0.0), vec3(1.0, -1.0, 0.0), 0.0);
+            let mut rng = SmallRng::seed_from_u64(83);
+            (0..10000).filter(|_| metal.scatter(&ray, &hit(metal), &mut rng).is_none()).count() as f32 / 10000.0
+        };
+        let white = vec4(1.0, 1.0, 1.0, 1.0);
+        let rate = rejected(&Metal::color(white, 1.0));
+        assert!(rate < 0.1, "Rejected {}", rate);
+        //unclamped, fuzz 5 would absorb almost 40% of them
+        assert_eq!(rejected(&Metal::color(white, 5.0)), rate);
+    }
+
+    #[cfg(feature = "serialize")]
+    #[test]
+    fn lambertian_round_trips() {
+        let material = Lambertian::color(vec4(0.8, 0.4, 0.2, 1.0));
+        let text = ron::to_string(&material).unwrap();
+        let back: Lambertian<SolidColor> = ron::from_str(&text).unwrap();
+        assert_eq!(ron::to_string(&back).unwrap(), text);
+
+        let mut rng = SmallRng::seed_from_u64(95);
+        let ray = Ray::new(Vec3::Y, -Vec3::Y, 0.0);
+        let a = material.scatter(&ray, &hit(&material), &mut rng).unwrap();
+        let b = back.scatter(&ray, &hit(&back), &mut rng).unwrap();
+        assert_eq!(a.attenuation, b.attenuation);
+    }
+
+    #[test]
+    fn image_lights_respect_transparency() {
+        //opaque red for u < 0.5 and transparent green above, image textures are mirrored in u
+        let mut image = RgbaImage::new(2, 1);
+        image.put_pixel(1, 0, Rgba([255, 0, 0, 255]));
+        image.put_pixel(0, 0, Rgba([0, 255, 0, 0]));
+        let light = DiffuseLight::with_intensity(ImageTexture::new(image), 4.0);
+        let at = |u: f32| HitResult { u, ..hit(&light) };
+
+        assert!(light.hack_solid(0.25, 0.5, Vec3::ZERO));
+        assert_eq!(light.emitted(&at(0.25)), vec4(4.0, 0.0, 0.0, 1.0));
+        assert!(!light.hack_solid(0.75, 0.5, Vec3::ZERO));
+
+        //rays go through the transparent half instead of picking up its emission
+        let mut rng = SmallRng::seed_from_u64(98);
+        let rect = XZRect::new(0.0, 1.0, 0.0, 1.0, 0.0, light);
+        let down = |x: f32| Ray::new(vec3(x, 1.0, 0.5), -Vec3::Y, 0.0);
+        assert!(rect.hit(&down(0.25), 0.001, f32::INFINITY, &mut rng).is_some());
+        assert!(rect.hit(&down(0.75), 0.001, f32::INFINITY, &mut rng).is_none());
+    }
+
+    #[test]
+    fn lights_emit_their_color() {
+        let color = vec4(1.0, 0.8, 0.5, 1.0);
+        let light = DiffuseLight::color(color);
+        let front = hit(&light);
+        let back = HitResult { front_face: false, normal: -Vec3::Y, ..hit(&light) };
+        //the same color the (u, v, p) version returned, from both sides
+        assert_eq!(light.emitted(&front), color);
+        assert_eq!(light.emitted(&back), color);
+
+        let bright = DiffuseLight::with_intensity(SolidColor::new(color), 3.0);
+        assert_eq!(bright.emitted(&HitResult { material: &bright, ..front }), vec4(3.0, 2.4, 1.5, 1.0));
+        let one_sided = DiffuseLight::color(color).with_two_sided(false);
+        assert_eq!(one_sided.emitted(&HitResult { material: &one_sided, ..front }), color);
+        assert_eq!(one_sided.emitted(&HitResult { material: &one_sided, ..back }), Vec4::ZERO);
+    }
+}
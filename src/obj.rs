@@ -1,12 +1,21 @@
+use std::io;
 use std::marker::PhantomData;
 use std::sync::Arc;
-use bevy_math::{Vec3, vec3};
+use bevy_math::{Mat3, Mat4, Vec3, vec3};
+use rand::Rng;
+use rand::rngs::SmallRng;
 use crate::aabb::AABB;
-use crate::material::Material;
-use crate::types::{Point3, Ray};
+use crate::aarect::PLANE_EXTENT;
+use crate::bvh::BvhNode;
+use crate::material::{Isotropic, Material};
+use crate::texture::{SolidColor, Texture};
+use crate::types::{Color, Point3, Ray};
+use crate::util::{Onb, random_to_sphere, unit_vector};
 
 pub struct HitResult<'a> {
     pub position: Point3,
+    //Where the hit is before Translate, Rotate or Transform moved the object, for textures in object space
+    pub local: Point3,
     pub normal: Vec3,
     pub t: f32,
     pub front_face: bool,
@@ -16,42 +25,119 @@ pub struct HitResult<'a> {
 }
 
 pub trait Hittable {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitResult>;
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult>;
 
     fn bounding_box(&self) -> AABB;
+
+    //Used for light sampling: probability density (over solid angle) of `random` returning `direction`.
+    //Must not depend on other objects blocking the way.
+    fn pdf_value(&self, _: Point3, _: Vec3, _: &mut SmallRng) -> f32 {
+        0.0
+    }
+
+    //Random direction from `origin` towards this object
+    fn random(&self, _: Point3, _: &mut SmallRng) -> Vec3 {
+        vec3(1.0, 0.0, 0.0)
+    }
+}
+
+//Lets one object appear in several places, each wrapped in its own transform
+impl<H: Hittable + ?Sized> Hittable for Arc<H> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
+        (**self).hit(ray, t_min, t_max, rng)
+    }
+
+    fn bounding_box(&self) -> AABB {
+        (**self).bounding_box()
+    }
+
+    fn pdf_value(&self, origin: Point3, direction: Vec3, rng: &mut SmallRng) -> f32 {
+        (**self).pdf_value(origin, direction, rng)
+    }
+
+    fn random(&self, origin: Point3, rng: &mut SmallRng) -> Vec3 {
+        (**self).random(origin, rng)
+    }
 }
 
 pub struct HittableList {
-    objs: Vec<Arc<dyn Hittable + Send>>,
+    objs: Vec<Arc<dyn Hittable + Send + Sync>>,
+    //Box around everything added so far, grown on every add
+    bbox: Option<AABB>,
 }
 
-unsafe impl Send for HittableList {}
-unsafe impl Sync for HittableList {}
+impl Default for HittableList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl HittableList {
     pub fn new() -> Self {
         Self {
             objs: Vec::new(),
+            bbox: None,
         }
     }
 
-    pub fn add(&mut self, obj: impl Hittable + Send + 'static) {
-        self.objs.push(Arc::new(obj));
+    pub fn add(&mut self, obj: impl Hittable + Send + Sync + 'static) {
+        self.add_shared(Arc::new(obj));
+    }
+
+    //For objects that need to be in multiple lists, eg lights that are also part of the scene
+    pub fn add_shared(&mut self, obj: Arc<dyn Hittable + Send + Sync>) {
+        let bbox = obj.bounding_box();
+        self.bbox = Some(match self.bbox {
+            Some(current) => AABB::surrounding_box(current, bbox),
+            None => bbox,
+        });
+        self.objs.push(obj);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objs.is_empty()
     }
 
     pub fn clear(&mut self) {
         self.objs.clear();
+        self.bbox = None;
     }
 
-    pub fn into_vec(self) -> Vec<Arc<dyn Hittable + Send>> {
+    pub fn into_vec(self) -> Vec<Arc<dyn Hittable + Send + Sync>> {
         self.objs
     }
 
-    pub fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitResult> {
+    //Puts every object in a BVH, so a ray only gets tested against the objects near it instead of all of them.
+    //The tree doesn't see objects added to the list later, build a new one after changing the scene
+    pub fn build_bvh(self) -> BvhNode {
+        assert!(!self.objs.is_empty(), "Can't build a BVH without objects");
+        BvhNode::new(&self.objs)
+    }
+
+    //Scenes are usually a flat list of a few big objects, each possibly with its own BVH inside. This puts them in
+    //a BVH too, so rays don't have to test every one of them. Planes and anything else with a box as big are left
+    //out and tested one by one next to the tree, their boxes would cover everything so every ray would visit them
+    //anyway, and through them the whole tree
+    pub fn accelerate(self) -> HittableList {
+        let (unbounded, bounded): (Vec<_>, Vec<_>) = self.objs.into_iter().partition(|obj| {
+            let bbox = obj.bounding_box();
+            (bbox.max - bbox.min).max_element() >= PLANE_EXTENT
+        });
+        let mut list = HittableList::new();
+        if !bounded.is_empty() {
+            list.add(BvhNode::new(&bounded));
+        }
+        for obj in unbounded {
+            list.add_shared(obj);
+        }
+        list
+    }
+
+    pub fn hit(&self, ray: Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
         let mut best = None;
         let mut closest = t_max;
         for obj in self.objs.iter() {
-            let res = obj.hit(&ray, t_min, closest);
+            let res = obj.hit(&ray, t_min, closest, rng);
             if let Some(res) = res {
                 closest = res.t;
                 best = Some(res);
@@ -61,17 +147,20 @@ impl HittableList {
     }
 
     pub fn bounding_box(&self) -> Option<AABB> {
-        if self.objs.is_empty() {
-            return None;
-        }
+        self.bbox
+    }
 
-        let mut iter = self.objs.iter();
-        let mut bbox = iter.next().unwrap().bounding_box();
-        for obj in iter {
-            bbox = AABB::surrounding_box(bbox, obj.bounding_box());
+    pub fn pdf_value(&self, origin: Point3, direction: Vec3, rng: &mut SmallRng) -> f32 {
+        if self.objs.is_empty() {
+            return 0.0;
         }
+        let sum: f32 = self.objs.iter().map(|o| o.pdf_value(origin, direction, rng)).sum();
+        sum / self.objs.len() as f32
+    }
 
-        Some(bbox)
+    pub fn random(&self, origin: Point3, rng: &mut SmallRng) -> Vec3 {
+        let idx = rng.gen_range(0..self.objs.len());
+        self.objs[idx].random(origin, rng)
     }
 }
 
@@ -91,31 +180,365 @@ impl<T: Material> Sphere<T> {
     }
 }
 
+//Whether a hit counts, textures with hack_solid cutouts or partial alpha let rays through
+pub fn is_solid(material: &dyn Material, u: f32, v: f32, p: Point3, rng: &mut SmallRng) -> bool {
+    if !material.hack_solid(u, v, p) {
+        return false;
+    }
+    let alpha = material.alpha(u, v, p);
+    alpha >= 1.0 || rng.gen::<f32>() < alpha
+}
+
+//Normals stay perpendicular to the surface when transformed by the inverse transpose of the matrix
+//applied to positions, which is only the same matrix for rotations. Scaling changes their length
+fn transform_normal(inverse: Mat3, normal: Vec3) -> Vec3 {
+    unit_vector(inverse.transpose() * normal)
+}
+
+fn hit_sphere<'a>(center: Point3, radius: f32, material: &'a dyn Material, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult<'a>> {
+    let oc = ray.origin - center;
+    let a = ray.direction.length_squared();
+    let half_b = oc.dot(ray.direction);
+    let c = oc.length_squared() - radius * radius;
+
+    let discriminant = half_b * half_b - a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+
+    let sqrt_disc = discriminant.sqrt();
+
+    let mut root = (-half_b - sqrt_disc) / a;
+    if root < t_min || root > t_max {
+        root = (-half_b + sqrt_disc) / a;
+        if root < t_min || root > t_max {
+            return None;
+        }
+    }
+
+    let t = root;
+    let p = ray.at(t);
+    let outward_normal = (p - center) / radius;
+    let front_face = ray.direction.dot(outward_normal) < 0.0;
+    let normal = if front_face {
+        outward_normal
+    } else {
+        -outward_normal
+    };
+
+    //from the direction to the center, so the texture moves with the sphere
+    let d = (p - center) / radius.abs();
+    let theta = (-d.y).clamp(-1.0, 1.0).acos();
+    let phi = (-d.z).atan2(d.x) + std::f32::consts::PI;
+
+    let u = phi / (2.0 * std::f32::consts::PI);
+    let v = theta / std::f32::consts::PI;
+
+    if !is_solid(material, u, v, p, rng) {
+        return None;
+    }
+
+    Some(HitResult {
+        position: p,
+        local: p,
+        normal,
+        t,
+        front_face,
+        material,
+        u,
+        v,
+    })
+}
+
 impl<T: Material> Hittable for Sphere<T> {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitResult> {
-        let oc = ray.origin - self.center;
-        let a = ray.direction.length_squared();
-        let half_b = oc.dot(ray.direction);
-        let c = oc.length_squared() - self.radius * self.radius;
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
+        hit_sphere(self.center, self.radius, &self.material, ray, t_min, t_max, rng)
+    }
+
+    fn bounding_box(&self) -> AABB {
+        let rv = Vec3::splat(self.radius);
+        AABB::new(self.center - rv, self.center + rv)
+    }
+
+    fn pdf_value(&self, origin: Point3, direction: Vec3, _: &mut SmallRng) -> f32 {
+        //uniform over the cone of directions that hit the sphere
+        let to_center = self.center - origin;
+        let distance_squared = to_center.length_squared();
+        if distance_squared <= self.radius * self.radius {
+            return 0.0;
+        }
+        let cos_theta_max = (1.0 - self.radius * self.radius / distance_squared).sqrt();
+        if unit_vector(direction).dot(to_center) < cos_theta_max * distance_squared.sqrt() {
+            return 0.0;
+        }
+        let solid_angle = 2.0 * std::f32::consts::PI * (1.0 - cos_theta_max);
+        1.0 / solid_angle
+    }
+
+    fn random(&self, origin: Point3, rng: &mut SmallRng) -> Vec3 {
+        let direction = self.center - origin;
+        let uvw = Onb::from_w(direction);
+        uvw.local(random_to_sphere(self.radius, direction.length_squared(), rng))
+    }
+}
+
+pub struct MovingSphere<T: Material> {
+    center0: Point3,
+    center1: Point3,
+    time0: f32,
+    time1: f32,
+    radius: f32,
+    material: T,
+}
+
+impl<T: Material> MovingSphere<T> {
+    //Moves linearly from center0 at time0 to center1 at time1
+    pub fn new(center0: Point3, center1: Point3, time0: f32, time1: f32, radius: f32, material: T) -> Self {
+        Self {
+            center0,
+            center1,
+            time0,
+            time1,
+            radius,
+            material,
+        }
+    }
+
+    pub fn center(&self, time: f32) -> Point3 {
+        self.center0 + ((time - self.time0) / (self.time1 - self.time0)) * (self.center1 - self.center0)
+    }
+}
 
+impl<T: Material> Hittable for MovingSphere<T> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
+        hit_sphere(self.center(ray.time), self.radius, &self.material, ray, t_min, t_max, rng)
+    }
+
+    fn bounding_box(&self) -> AABB {
+        let rv = Vec3::splat(self.radius);
+        AABB::surrounding_box(
+            AABB::new(self.center0 - rv, self.center0 + rv),
+            AABB::new(self.center1 - rv, self.center1 + rv),
+        )
+    }
+}
+
+//Sphere stretched by radii along each axis
+pub struct Ellipsoid<T: Material> {
+    center: Point3,
+    radii: Vec3,
+    material: T,
+}
+
+impl<T: Material> Ellipsoid<T> {
+    pub fn new(center: Point3, radii: Vec3, material: T) -> Self {
+        Self {
+            center,
+            radii,
+            material,
+        }
+    }
+}
+
+impl<T: Material> Hittable for Ellipsoid<T> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
+        //in the space where the ellipsoid is a unit sphere at the origin t doesn't change,
+        //so the sphere hit can be used as is and moved back
+        let local = Ray::new((ray.origin - self.center) / self.radii, ray.direction / self.radii, ray.time);
+        let mut res = hit_sphere(Vec3::ZERO, 1.0, &self.material, &local, t_min, t_max, rng)?;
+        res.position = ray.at(res.t);
+        res.normal = transform_normal(Mat3::from_diagonal(1.0 / self.radii), res.normal);
+        Some(res)
+    }
+
+    fn bounding_box(&self) -> AABB {
+        AABB::new(self.center - self.radii, self.center + self.radii)
+    }
+}
+
+//Finite cylinder between two points, the ends are open unless capped
+pub struct Cylinder<T: Material> {
+    a: Point3,
+    b: Point3,
+    radius: f32,
+    capped: bool,
+    //around the axis (w), for the u coordinate
+    frame: Onb,
+    height: f32,
+    material: T,
+}
+
+impl<T: Material> Cylinder<T> {
+    pub fn new(a: Point3, b: Point3, radius: f32, capped: bool, material: T) -> Self {
+        Self {
+            a,
+            b,
+            radius,
+            capped,
+            frame: Onb::from_w(b - a),
+            height: (b - a).length(),
+            material,
+        }
+    }
+
+    fn angle_u(&self, offset: Vec3) -> f32 {
+        let angle = offset.dot(self.frame.v).atan2(offset.dot(self.frame.u));
+        angle.rem_euclid(2.0 * std::f32::consts::PI) / (2.0 * std::f32::consts::PI)
+    }
+}
+
+impl<T: Material> Hittable for Cylinder<T> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
+        let axis = self.frame.w;
+        let oc = ray.origin - self.a;
+        //closest hit so far as (t, outward normal, u, v)
+        let mut closest: Option<(f32, Vec3, f32, f32)> = None;
+        let mut t_max = t_max;
+
+        //side: quadratic in the components perpendicular to the axis
+        let d_perp = ray.direction - axis * ray.direction.dot(axis);
+        let oc_perp = oc - axis * oc.dot(axis);
+        let a = d_perp.length_squared();
+        let half_b = d_perp.dot(oc_perp);
+        let c = oc_perp.length_squared() - self.radius * self.radius;
         let discriminant = half_b * half_b - a * c;
-        if discriminant < 0.0 {
+        if a > 1e-12 && discriminant >= 0.0 {
+            let sqrtd = discriminant.sqrt();
+            for t in [(-half_b - sqrtd) / a, (-half_b + sqrtd) / a] {
+                if t < t_min || t > t_max {
+                    continue;
+                }
+                let offset = oc + t * ray.direction;
+                let y = offset.dot(axis);
+                if y < 0.0 || y > self.height {
+                    continue;
+                }
+                let radial = offset - axis * y;
+                closest = Some((t, radial / self.radius, self.angle_u(radial), y / self.height));
+                t_max = t;
+                break;
+            }
+        }
+
+        if self.capped {
+            let denom = ray.direction.dot(axis);
+            if denom.abs() > 1e-8 {
+                for (center, normal, v) in [(self.a, -axis, 0.0), (self.b, axis, 1.0)] {
+                    let t = (center - ray.origin).dot(axis) / denom;
+                    if t < t_min || t > t_max {
+                        continue;
+                    }
+                    let offset = ray.at(t) - center;
+                    if offset.length_squared() > self.radius * self.radius {
+                        continue;
+                    }
+                    closest = Some((t, normal, self.angle_u(offset), v));
+                    t_max = t;
+                }
+            }
+        }
+
+        let (t, outward_normal, u, v) = closest?;
+        let position = ray.at(t);
+        let front_face = ray.direction.dot(outward_normal) < 0.0;
+        let normal = if front_face {
+            outward_normal
+        } else {
+            -outward_normal
+        };
+        if !is_solid(&self.material, u, v, position, rng) {
             return None;
         }
 
-        let sqrt_disc = discriminant.sqrt();
+        Some(HitResult {
+            position,
+            local: position,
+            normal,
+            t,
+            front_face,
+            material: &self.material,
+            u,
+            v,
+        })
+    }
 
-        let mut root = (-half_b - sqrt_disc) / a;
-        if root < t_min || root > t_max {
-            root = (-half_b + sqrt_disc) / a;
-            if root < t_min || root > t_max {
-                return None;
-            }
+    fn bounding_box(&self) -> AABB {
+        //the end disks reach radius * sin(angle between axis and coordinate axis) along each axis
+        let w = self.frame.w;
+        let extent = self.radius * vec3(
+            (1.0 - w.x * w.x).max(0.0).sqrt(),
+            (1.0 - w.y * w.y).max(0.0).sqrt(),
+            (1.0 - w.z * w.z).max(0.0).sqrt(),
+        );
+        AABB::new(self.a.min(self.b) - extent, self.a.max(self.b) + extent)
+    }
+}
+
+pub struct Triangle<T: Material> {
+    a: Point3,
+    b: Point3,
+    c: Point3,
+    normals: Option<[Vec3; 3]>,
+    material: T,
+}
+
+impl<T: Material> Triangle<T> {
+    pub fn new(a: Point3, b: Point3, c: Point3, material: T) -> Self {
+        Self {
+            a,
+            b,
+            c,
+            normals: None,
+            material,
+        }
+    }
+
+    pub fn with_normals(a: Point3, b: Point3, c: Point3, normals: [Vec3; 3], material: T) -> Self {
+        Self {
+            a,
+            b,
+            c,
+            normals: Some(normals),
+            material,
+        }
+    }
+}
+
+impl<T: Material> Hittable for Triangle<T> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
+        //Möller–Trumbore
+        let edge1 = self.b - self.a;
+        let edge2 = self.c - self.a;
+
+        let h = ray.direction.cross(edge2);
+        let det = edge1.dot(h);
+        if det.abs() < 1e-8 {
+            return None;
+        }
+
+        let inv_det = 1.0 / det;
+        let s = ray.origin - self.a;
+        let u = inv_det * s.dot(h);
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+
+        let q = s.cross(edge1);
+        let v = inv_det * ray.direction.dot(q);
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+
+        let t = inv_det * edge2.dot(q);
+        if t < t_min || t > t_max {
+            return None;
         }
 
-        let t = root;
         let p = ray.at(t);
-        let outward_normal = (p - self.center) / self.radius;
+        let outward_normal = match self.normals {
+            Some([na, nb, nc]) => ((1.0 - u - v) * na + u * nb + v * nc).normalize(),
+            None => edge1.cross(edge2).normalize(),
+        };
         let front_face = ray.direction.dot(outward_normal) < 0.0;
         let normal = if front_face {
             outward_normal
@@ -123,18 +546,89 @@ impl<T: Material> Hittable for Sphere<T> {
             -outward_normal
         };
 
-        let theta = (-p.y).acos();
-        let phi = (-p.z).atan2(p.x) + std::f32::consts::PI;
+        if !is_solid(&self.material, u, v, p, rng) {
+            return None;
+        }
 
-        let u = phi / (2.0 * std::f32::consts::PI);
-        let v = theta / std::f32::consts::PI;
+        Some(HitResult {
+            position: p,
+            local: p,
+            normal,
+            t,
+            front_face,
+            material: &self.material,
+            u,
+            v,
+        })
+    }
+
+    fn bounding_box(&self) -> AABB {
+        //pad so triangles lying on an axis plane still have some thickness
+        let min = self.a.min(self.b).min(self.c);
+        let max = self.a.max(self.b).max(self.c);
+        AABB::new(min, max).pad(0.0002)
+    }
+}
+
+//Axis aligned box. With an outline only a band of the given width along its edges is solid,
+//which is handy to see bounding boxes without hiding what's inside them
+pub struct Cuboid<T: Material> {
+    bbox: AABB,
+    outline: Option<f32>,
+    material: T,
+}
+
+impl<T: Material> Cuboid<T> {
+    pub fn new(min: Point3, max: Point3, material: T) -> Self {
+        Self::from_aabb(AABB::new(min.min(max), min.max(max)), material)
+    }
+
+    pub fn from_aabb(bbox: AABB, material: T) -> Self {
+        Self {
+            bbox,
+            outline: None,
+            material,
+        }
+    }
+
+    pub fn with_outline(mut self, width: f32) -> Self {
+        self.outline = Some(width);
+        self
+    }
 
-        if !self.material.hack_solid(u, v, p) {
+    //Hit on the face perpendicular to `axis`, None if it falls inside the outline
+    fn face_hit(&self, ray: &Ray, t: f32, axis: usize, rng: &mut SmallRng) -> Option<HitResult> {
+        let position = ray.at(t);
+        let p = position.to_array();
+        let min = self.bbox.min.to_array();
+        let max = self.bbox.max.to_array();
+        let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+        if let Some(width) = self.outline {
+            let near_edge = |i: usize| p[i] - min[i] < width || max[i] - p[i] < width;
+            if !near_edge(a) && !near_edge(b) {
+                return None;
+            }
+        }
+
+        let u = ((p[a] - min[a]) / (max[a] - min[a])).clamp(0.0, 1.0);
+        let v = ((p[b] - min[b]) / (max[b] - min[b])).clamp(0.0, 1.0);
+        if !is_solid(&self.material, u, v, position, rng) {
             return None;
         }
 
+        let mut outward_normal = [0.0; 3];
+        outward_normal[axis] = if p[axis] - min[axis] < max[axis] - p[axis] { -1.0 } else { 1.0 };
+        let outward_normal = Vec3::from_array(outward_normal);
+        let front_face = ray.direction.dot(outward_normal) < 0.0;
+        let normal = if front_face {
+            outward_normal
+        } else {
+            -outward_normal
+        };
+
         Some(HitResult {
-            position: p,
+            position,
+            local: position,
             normal,
             t,
             front_face,
@@ -143,13 +637,198 @@ impl<T: Material> Hittable for Sphere<T> {
             v,
         })
     }
+}
+
+impl<T: Material> Hittable for Cuboid<T> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
+        let min = self.bbox.min.to_array();
+        let max = self.bbox.max.to_array();
+        let origin = ray.origin.to_array();
+        let direction = ray.direction.to_array();
+
+        //same slab test as AABB::hit, also keeping track of which faces the ray enters and leaves through
+        let (mut near, mut far) = (f32::NEG_INFINITY, f32::INFINITY);
+        let (mut near_axis, mut far_axis) = (0, 0);
+        for i in 0..3 {
+            if direction[i] == 0.0 {
+                if origin[i] < min[i] || origin[i] > max[i] {
+                    return None;
+                }
+                continue;
+            }
+            let inv_d = 1.0 / direction[i];
+            let mut t0 = (min[i] - origin[i]) * inv_d;
+            let mut t1 = (max[i] - origin[i]) * inv_d;
+            if inv_d < 0.0 {
+                (t0, t1) = (t1, t0);
+            }
+            if t0 > near {
+                near = t0;
+                near_axis = i;
+            }
+            if t1 < far {
+                far = t1;
+                far_axis = i;
+            }
+        }
+        if far < near {
+            return None;
+        }
+
+        //the far face is only needed if the near one is behind the ray or not part of the outline
+        if near >= t_min && near <= t_max {
+            if let Some(hit) = self.face_hit(ray, near, near_axis, rng) {
+                return Some(hit);
+            }
+        }
+        if far >= t_min && far <= t_max {
+            return self.face_hit(ray, far, far_axis, rng);
+        }
+        None
+    }
 
     fn bounding_box(&self) -> AABB {
-        let rv = Vec3::splat(self.radius);
-        AABB::new(self.center - rv, self.center + rv)
+        self.bbox.pad(0.0002)
+    }
+}
+
+pub struct ConstantMedium<B: Hittable, T: Texture> {
+    boundary: B,
+    neg_inv_density: f32,
+    phase_function: Isotropic<T>,
+}
+
+impl<B: Hittable, T: Texture> ConstantMedium<B, T> {
+    pub fn new(boundary: B, density: f32, texture: T) -> Self {
+        Self {
+            boundary,
+            neg_inv_density: -1.0 / density,
+            phase_function: Isotropic::new(texture),
+        }
+    }
+}
+
+impl<B: Hittable> ConstantMedium<B, SolidColor> {
+    pub fn color(boundary: B, density: f32, color: Color) -> Self {
+        Self::new(boundary, density, SolidColor::new(color))
     }
 }
 
+impl<B: Hittable, T: Texture> Hittable for ConstantMedium<B, T> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
+        //find where the ray enters and leaves the boundary, even if the origin is inside it
+        let t1 = self.boundary.hit(ray, f32::NEG_INFINITY, f32::INFINITY, rng)?.t;
+        let t2 = self.boundary.hit(ray, t1 + 0.0001, f32::INFINITY, rng)?.t;
+
+        let t1 = t1.max(t_min).max(0.0);
+        let t2 = t2.min(t_max);
+        if t1 >= t2 {
+            return None;
+        }
+
+        let ray_length = ray.direction.length();
+        let distance_inside = (t2 - t1) * ray_length;
+        let hit_distance = self.neg_inv_density * rng.gen::<f32>().ln();
+        if hit_distance > distance_inside {
+            return None;
+        }
+
+        let t = t1 + hit_distance / ray_length;
+        let position = ray.at(t);
+        Some(HitResult {
+            position,
+            local: position,
+            //arbitrary, isotropic scattering ignores the normal
+            normal: vec3(1.0, 0.0, 0.0),
+            t,
+            front_face: true,
+            material: &self.phase_function,
+            u: 0.0,
+            v: 0.0,
+        })
+    }
+
+    fn bounding_box(&self) -> AABB {
+        self.boundary.bounding_box()
+    }
+}
+
+fn parse_obj_index(s: &str, len: usize) -> io::Result<usize> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Invalid index '{}'", s));
+    let i: isize = s.parse().map_err(|_| invalid())?;
+    //OBJ indices are 1-based, negative values are relative to the end of the list
+    let i = if i < 0 { len as isize + i } else { i - 1 };
+    if i < 0 || i as usize >= len {
+        return Err(invalid());
+    }
+    Ok(i as usize)
+}
+
+fn parse_obj_vec3<'a>(mut parts: impl Iterator<Item = &'a str>) -> io::Result<Vec3> {
+    let mut next = || -> io::Result<f32> {
+        parts.next()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid vector"))
+    };
+    Ok(vec3(next()?, next()?, next()?))
+}
+
+//Loads the geometry of a Wavefront OBJ file, materials from .mtl files are ignored.
+//Faces with more than three vertices are split into a triangle fan.
+pub fn load_obj<T: Material + Clone + Send + Sync + 'static>(path: &str, material: T) -> io::Result<BvhNode> {
+    let data = std::fs::read_to_string(path)?;
+
+    let mut vertices = Vec::new();
+    let mut normals = Vec::new();
+    let mut triangles = HittableList::new();
+
+    for line in data.lines() {
+        let mut parts = line.split_whitespace();
+        match parts.next() {
+            Some("v") => vertices.push(parse_obj_vec3(parts)?),
+            Some("vn") => normals.push(parse_obj_vec3(parts)?.normalize()),
+            Some("f") => {
+                let mut face = Vec::new();
+                for part in parts {
+                    //v, v/vt, v//vn or v/vt/vn
+                    let mut indices = part.split('/');
+                    let v = parse_obj_index(indices.next().unwrap(), vertices.len())?;
+                    let n = match indices.nth(1) {
+                        Some(n) if !n.is_empty() => Some(parse_obj_index(n, normals.len())?),
+                        _ => None,
+                    };
+                    face.push((v, n));
+                }
+                if face.len() < 3 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Face with less than 3 vertices"));
+                }
+
+                let (a, na) = face[0];
+                for i in 1..(face.len() - 1) {
+                    let (b, nb) = face[i];
+                    let (c, nc) = face[i + 1];
+                    let (a, b, c) = (vertices[a], vertices[b], vertices[c]);
+                    match (na, nb, nc) {
+                        (Some(na), Some(nb), Some(nc)) => triangles.add(Triangle::with_normals(
+                            a, b, c,
+                            [normals[na], normals[nb], normals[nc]],
+                            material.clone(),
+                        )),
+                        _ => triangles.add(Triangle::new(a, b, c, material.clone())),
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let triangles = triangles.into_vec();
+    if triangles.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "No faces in OBJ file"));
+    }
+    Ok(BvhNode::new(&triangles[..]))
+}
+
 pub trait RotateVec3 {
     fn rotate(v: Vec3, sin_theta: f32, cos_theta: f32) -> Vec3;
 }
@@ -225,27 +904,18 @@ impl<O: Hittable, R: RotateVec3> Rotate<O, R> {
 }
 
 impl<O: Hittable, R: RotateVec3> Hittable for Rotate<O, R> {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitResult> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
         //-self.sin_theta because sin(-X) = -sin(X), cos(-X) = cos(X)
         let origin = R::rotate(ray.origin, -self.sin_theta, self.cos_theta);
         let direction = R::rotate(ray.direction, -self.sin_theta, self.cos_theta);
 
-        let rotated_ray = Ray::new(origin, direction);
-        let mut res = self.obj.hit(&rotated_ray, t_min, t_max)?;
+        let rotated_ray = Ray::new(origin, direction, ray.time);
+        let mut res = self.obj.hit(&rotated_ray, t_min, t_max, rng)?;
 
-        let p = R::rotate(res.position, self.sin_theta, self.cos_theta);
-        let normal = R::rotate(res.normal, self.sin_theta, self.cos_theta);
-
-        let front_face = rotated_ray.direction.dot(normal) < 0.0;
-        let normal = if front_face {
-            normal
-        } else {
-            -normal
-        };
-
-        res.position = p;
-        res.front_face = front_face;
-        res.normal = normal;
+        //the inverse transpose of a rotation is the rotation itself. Rotating both the normal and the ray keeps
+        //the angle between them, so the normal still faces against the original ray and front_face is unchanged
+        res.position = R::rotate(res.position, self.sin_theta, self.cos_theta);
+        res.normal = unit_vector(R::rotate(res.normal, self.sin_theta, self.cos_theta));
 
         Some(res)
     }
@@ -253,6 +923,17 @@ impl<O: Hittable, R: RotateVec3> Hittable for Rotate<O, R> {
     fn bounding_box(&self) -> AABB {
         self.bbox
     }
+
+    fn pdf_value(&self, origin: Point3, direction: Vec3, rng: &mut SmallRng) -> f32 {
+        let origin = R::rotate(origin, -self.sin_theta, self.cos_theta);
+        let direction = R::rotate(direction, -self.sin_theta, self.cos_theta);
+        self.obj.pdf_value(origin, direction, rng)
+    }
+
+    fn random(&self, origin: Point3, rng: &mut SmallRng) -> Vec3 {
+        let origin = R::rotate(origin, -self.sin_theta, self.cos_theta);
+        R::rotate(self.obj.random(origin, rng), self.sin_theta, self.cos_theta)
+    }
 }
 
 pub type RotateX<O> = Rotate<O, RotateVec3X>;
@@ -278,9 +959,9 @@ impl<O: Hittable> Translate<O> {
 }
 
 impl<O: Hittable> Hittable for Translate<O> {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitResult> {
-        let moved_ray = Ray::new(ray.origin - self.translation, ray.direction);
-        let mut res = self.obj.hit(&moved_ray, t_min, t_max)?;
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
+        let moved_ray = Ray::new(ray.origin - self.translation, ray.direction, ray.time);
+        let mut res = self.obj.hit(&moved_ray, t_min, t_max, rng)?;
 
         let front_face = moved_ray.direction.dot(res.normal) < 0.0;
         let normal = if front_face {
@@ -299,4 +980,401 @@ impl<O: Hittable> Hittable for Translate<O> {
     fn bounding_box(&self) -> AABB {
         self.bbox
     }
+
+    fn pdf_value(&self, origin: Point3, direction: Vec3, rng: &mut SmallRng) -> f32 {
+        self.obj.pdf_value(origin - self.translation, direction, rng)
+    }
+
+    fn random(&self, origin: Point3, rng: &mut SmallRng) -> Vec3 {
+        self.obj.random(origin - self.translation, rng)
+    }
+}
+
+//Arbitrary affine transform, composing several of these is a lot cheaper than nesting
+//Rotate/Translate wrappers. Light sampling through it is only exact for rigid transforms
+pub struct Transform<O: Hittable> {
+    obj: O,
+    matrix: Mat4,
+    inverse: Mat4,
+    bbox: AABB,
+}
+
+impl<O: Hittable> Transform<O> {
+    pub fn new(obj: O) -> Self {
+        Self::from_matrix(obj, Mat4::IDENTITY)
+    }
+
+    pub fn from_matrix(obj: O, matrix: Mat4) -> Self {
+        let bbox = obj.bounding_box();
+        let mut res = Self {
+            obj,
+            matrix: Mat4::IDENTITY,
+            inverse: Mat4::IDENTITY,
+            bbox,
+        };
+        res.apply(matrix);
+        res
+    }
+
+    //Each call applies on top of the previous ones, so `.rotate_x(a).translate(v)` rotates first
+    pub fn translate(mut self, v: Vec3) -> Self {
+        self.apply(Mat4::from_translation(v));
+        self
+    }
+
+    pub fn rotate_x(mut self, angle: f32) -> Self {
+        self.apply(Mat4::from_rotation_x(angle));
+        self
+    }
+
+    pub fn rotate_y(mut self, angle: f32) -> Self {
+        self.apply(Mat4::from_rotation_y(angle));
+        self
+    }
+
+    pub fn rotate_z(mut self, angle: f32) -> Self {
+        self.apply(Mat4::from_rotation_z(angle));
+        self
+    }
+
+    pub fn scale(mut self, s: Vec3) -> Self {
+        self.apply(Mat4::from_scale(s));
+        self
+    }
+
+    fn apply(&mut self, m: Mat4) {
+        self.matrix = m * self.matrix;
+        self.inverse = self.matrix.inverse();
+
+        let orig_bbox = self.obj.bounding_box();
+        let mut min = Vec3::splat(f32::INFINITY);
+        let mut max = Vec3::splat(f32::NEG_INFINITY);
+        for i in 0..8 {
+            let corner = vec3(
+                if i & 1 == 0 { orig_bbox.min.x } else { orig_bbox.max.x },
+                if i & 2 == 0 { orig_bbox.min.y } else { orig_bbox.max.y },
+                if i & 4 == 0 { orig_bbox.min.z } else { orig_bbox.max.z },
+            );
+            let p = self.matrix.transform_point3(corner);
+            min = min.min(p);
+            max = max.max(p);
+        }
+        self.bbox = AABB::new(min, max);
+    }
+}
+
+impl<O: Hittable> Hittable for Transform<O> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
+        //the direction isn't normalized, so t is the same in both spaces
+        let origin = self.inverse.transform_point3(ray.origin);
+        let direction = self.inverse.transform_vector3(ray.direction);
+        let local_ray = Ray::new(origin, direction, ray.time);
+        let mut res = self.obj.hit(&local_ray, t_min, t_max, rng)?;
+
+        //the inverse transpose also keeps normals facing against the ray
+        res.position = ray.at(res.t);
+        res.normal = transform_normal(Mat3::from_mat4(self.inverse), res.normal);
+
+        Some(res)
+    }
+
+    fn bounding_box(&self) -> AABB {
+        self.bbox
+    }
+
+    fn pdf_value(&self, origin: Point3, direction: Vec3, rng: &mut SmallRng) -> f32 {
+        let origin = self.inverse.transform_point3(origin);
+        let direction = self.inverse.transform_vector3(direction);
+        self.obj.pdf_value(origin, direction, rng)
+    }
+
+    fn random(&self, origin: Point3, rng: &mut SmallRng) -> Vec3 {
+        let origin = self.inverse.transform_point3(origin);
+        self.matrix.transform_vector3(self.obj.random(origin, rng))
+    }
+}
+
+//Scales an object around the origin. Normals are scaled by the inverse factor so non-uniform
+//scaling still shades correctly, but light sampling through it is only exact when uniform
+pub struct Scale<O: Hittable> {
+    obj: O,
+    factor: Vec3,
+    bbox: AABB,
+}
+
+impl<O: Hittable> Scale<O> {
+    pub fn new(obj: O, factor: f32) -> Self {
+        Self::per_axis(obj, Vec3::splat(factor))
+    }
+
+    pub fn per_axis(obj: O, factor: Vec3) -> Self {
+        let bbox = obj.bounding_box();
+        let (a, b) = (bbox.min * factor, bbox.max * factor);
+        let bbox = AABB::new(a.min(b), a.max(b));
+        Self {
+            obj,
+            factor,
+            bbox,
+        }
+    }
+}
+
+impl<O: Hittable> Hittable for Scale<O> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
+        let scaled_ray = Ray::new(ray.origin / self.factor, ray.direction / self.factor, ray.time);
+        let mut res = self.obj.hit(&scaled_ray, t_min, t_max, rng)?;
+
+        res.position *= self.factor;
+        res.normal = transform_normal(Mat3::from_diagonal(1.0 / self.factor), res.normal);
+
+        Some(res)
+    }
+
+    fn bounding_box(&self) -> AABB {
+        self.bbox
+    }
+
+    fn pdf_value(&self, origin: Point3, direction: Vec3, rng: &mut SmallRng) -> f32 {
+        self.obj.pdf_value(origin / self.factor, direction / self.factor, rng)
+    }
+
+    fn random(&self, origin: Point3, rng: &mut SmallRng) -> Vec3 {
+        self.obj.random(origin / self.factor, rng) * self.factor
+    }
+}
+
+//Swaps which side of the object counts as the front, for inverted geometry or one sided lights
+pub struct FlipFace<O: Hittable> {
+    obj: O,
+}
+
+impl<O: Hittable> FlipFace<O> {
+    pub fn new(obj: O) -> Self {
+        Self {
+            obj,
+        }
+    }
+}
+
+impl<O: Hittable> Hittable for FlipFace<O> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut SmallRng) -> Option<HitResult> {
+        let mut res = self.obj.hit(ray, t_min, t_max, rng)?;
+        res.front_face = !res.front_face;
+        res.normal = -res.normal;
+        Some(res)
+    }
+
+    fn bounding_box(&self) -> AABB {
+        self.obj.bounding_box()
+    }
+
+    fn pdf_value(&self, origin: Point3, direction: Vec3, rng: &mut SmallRng) -> f32 {
+        self.obj.pdf_value(origin, direction, rng)
+    }
+
+    fn random(&self, origin: Point3, rng: &mut SmallRng) -> Vec3 {
+        self.obj.random(origin, rng)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_math::vec4;
+    use rand::SeedableRng;
+    use crate::aarect::Plane;
+    use crate::bvh::{enable_node_visits, node_visits};
+    use crate::material::Lambertian;
+    use crate::util::{random_in_unit_sphere, random_unit_vector};
+    use super::*;
+
+    fn gray() -> Lambertian<SolidColor> {
+        Lambertian::color(vec4(0.5, 0.5, 0.5, 1.0))
+    }
+
+    #[test]
+    fn scene_is_shared_between_threads() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+        let mut objs = HittableList::new();
+        for i in 0..8 {
+            objs.add(Sphere::new(vec3(i as f32, 0.0, -5.0), 0.4, gray()));
+        }
+        let mut scene = HittableList::new();
+        scene.add(objs.build_bvh());
+        assert_send_sync(&scene);
+
+        //every thread borrows the same list, no copies or unsafe needed
+        let scene = &scene;
+        std::thread::scope(|s| {
+            for i in 0..8 {
+                s.spawn(move || {
+                    let mut rng = SmallRng::seed_from_u64(i);
+                    let ray = Ray::new(vec3(i as f32, 0.0, 0.0), vec3(0.0, 0.0, -1.0), 0.0);
+                    let hit = scene.hit(ray, 0.001, f32::INFINITY, &mut rng).unwrap();
+                    assert!((hit.t - 4.6).abs() < 1e-4);
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn flip_face_inverts_the_hit() {
+        let sphere = Arc::new(Sphere::new(Vec3::ZERO, 1.0, gray()));
+        let flipped = FlipFace::new(sphere.clone());
+        let ray = Ray::new(vec3(0.0, 0.0, 5.0), vec3(0.0, 0.0, -1.0), 0.0);
+        let mut rng = SmallRng::seed_from_u64(1);
+
+        let hit = sphere.hit(&ray, 0.001, f32::INFINITY, &mut rng).unwrap();
+        let flipped_hit = flipped.hit(&ray, 0.001, f32::INFINITY, &mut rng).unwrap();
+        assert!(hit.front_face);
+        assert!(!flipped_hit.front_face);
+        assert_eq!(hit.normal, Vec3::Z);
+        assert_eq!(flipped_hit.normal, -Vec3::Z);
+        assert_eq!(hit.t, flipped_hit.t);
+    }
+
+    #[test]
+    fn flat_triangle_has_padded_box() {
+        let triangle = Triangle::new(vec3(0.0, 0.0, 2.0), vec3(1.0, 0.0, 2.0), vec3(0.0, 1.0, 2.0), gray());
+        let bbox = triangle.bounding_box();
+        let extent = bbox.max - bbox.min;
+        assert!(extent.min_element() > 0.0, "Got {}", extent);
+        assert!(bbox.min.z < 2.0 && bbox.max.z > 2.0);
+        //a ray straight through it isn't rejected by the box
+        let ray = Ray::new(vec3(0.25, 0.25, 5.0), vec3(0.0, 0.0, -1.0), 0.0);
+        assert!(bbox.hit(&ray, 0.001, f32::INFINITY));
+    }
+
+    #[test]
+    fn sphere_uv_follows_the_center() {
+        let sphere = Sphere::new(vec3(5.0, -3.0, 2.0), 2.0, gray());
+        let mut rng = SmallRng::seed_from_u64(1);
+        //straight down onto the top pole, then up into the bottom one
+        let ray = Ray::new(vec3(5.0, 10.0, 2.0), vec3(0.0, -1.0, 0.0), 0.0);
+        let top = sphere.hit(&ray, 0.001, f32::INFINITY, &mut rng).unwrap();
+        assert!((top.v - 1.0).abs() < 1e-4, "Got v = {}", top.v);
+        let ray = Ray::new(vec3(5.0, -10.0, 2.0), vec3(0.0, 1.0, 0.0), 0.0);
+        let bottom = sphere.hit(&ray, 0.001, f32::INFINITY, &mut rng).unwrap();
+        assert!(bottom.v.abs() < 1e-4, "Got v = {}", bottom.v);
+    }
+
+    //Shoots rays from all around at obj, which has to be convex and contain the origin, checking the normals
+    //are unit length and point outwards
+    fn assert_unit_normals(obj: &dyn Hittable) {
+        let mut rng = SmallRng::seed_from_u64(85);
+        let mut hits = 0;
+        for _ in 0..1000 {
+            let origin = 10.0 * random_unit_vector(&mut rng);
+            let target = 0.5 * random_in_unit_sphere(&mut rng);
+            let Some(hit) = obj.hit(&Ray::new(origin, target - origin, 0.0), 0.001, f32::INFINITY, &mut rng) else {
+                continue;
+            };
+            hits += 1;
+            assert!((hit.normal.length() - 1.0).abs() < 1e-5, "Got normal {} with length {}", hit.normal, hit.normal.length());
+            assert!(hit.front_face && hit.normal.dot(hit.position) > 0.0, "Normal {} points inwards at {}", hit.normal, hit.position);
+        }
+        assert!(hits > 500);
+    }
+
+    #[test]
+    fn scaled_normals_stay_unit_length() {
+        let radii = vec3(2.0, 0.5, 1.0);
+        assert_unit_normals(&Ellipsoid::new(Vec3::ZERO, radii, gray()));
+        assert_unit_normals(&Scale::per_axis(Sphere::new(Vec3::ZERO, 1.0, gray()), radii));
+        assert_unit_normals(&Scale::new(Sphere::new(Vec3::ZERO, 1.0, gray()), 3.0));
+        let transform = Transform::new(Sphere::new(Vec3::ZERO, 1.0, gray()))
+            .scale(radii)
+            .rotate_z(0.7)
+            .rotate_x(-1.2);
+        assert_unit_normals(&transform);
+
+        //scaling a sphere along an axis gives the same normals as the ellipsoid
+        let mut rng = SmallRng::seed_from_u64(1);
+        let ray = Ray::new(vec3(5.0, 4.0, 3.0), vec3(-5.0, -4.0, -3.0), 0.0);
+        let ellipsoid = Ellipsoid::new(Vec3::ZERO, radii, gray());
+        let scaled = Scale::per_axis(Sphere::new(Vec3::ZERO, 1.0, gray()), radii);
+        let a = ellipsoid.hit(&ray, 0.001, f32::INFINITY, &mut rng).unwrap();
+        let b = scaled.hit(&ray, 0.001, f32::INFINITY, &mut rng).unwrap();
+        assert!((a.normal - b.normal).length() < 1e-5, "{} != {}", a.normal, b.normal);
+    }
+
+    #[test]
+    fn rotated_normals_stay_unit_length() {
+        //three rotations in a row like the mogu's, over a shape whose normals aren't radial
+        let ellipsoid = Ellipsoid::new(Vec3::ZERO, vec3(2.0, 0.5, 1.0), gray());
+        let rotated = RotateX::new(RotateY::new(RotateZ::new(ellipsoid, 0.4), 2.1), -0.9);
+        assert_unit_normals(&rotated);
+    }
+
+    #[test]
+    fn list_box_is_cached() {
+        let fresh = |list: &HittableList| list.objs.iter()
+            .map(|o| o.bounding_box())
+            .reduce(AABB::surrounding_box)
+            .unwrap();
+        let same = |a: AABB, b: AABB| a.min == b.min && a.max == b.max;
+
+        let mut list = HittableList::new();
+        assert!(list.bounding_box().is_none());
+        let mut rng = SmallRng::seed_from_u64(103);
+        for _ in 0..50 {
+            list.add(Sphere::new(random_in_unit_sphere(&mut rng) * 10.0, rng.gen_range(0.1..1.0), gray()));
+            assert!(same(list.bounding_box().unwrap(), fresh(&list)));
+        }
+
+        //adding something outside grows the cached box
+        list.add(Sphere::new(vec3(100.0, 0.0, 0.0), 1.0, gray()));
+        let bbox = list.bounding_box().unwrap();
+        assert!(same(bbox, fresh(&list)));
+        assert_eq!(bbox.max.x, 101.0);
+
+        list.clear();
+        assert!(list.bounding_box().is_none());
+    }
+
+    //1000 small spheres scattered through a cube, with a floor plane under them
+    fn flat_scene() -> HittableList {
+        let mut rng = SmallRng::seed_from_u64(104);
+        let mut list = HittableList::new();
+        for _ in 0..1000 {
+            let center = vec3(rng.gen_range(-50.0..50.0), rng.gen_range(0.0..100.0), rng.gen_range(-50.0..50.0));
+            list.add(Sphere::new(center, rng.gen_range(0.5..1.5), gray()));
+        }
+        list.add(Plane::new(vec3(0.0, -1.0, 0.0), Vec3::Y, gray()));
+        list
+    }
+
+    #[test]
+    fn accelerate_keeps_planes_out_of_the_tree() {
+        let accelerated = flat_scene().accelerate();
+        //the tree and the plane next to it
+        assert_eq!(accelerated.objs.len(), 2);
+        let tree = accelerated.objs[0].bounding_box();
+        assert!((tree.max - tree.min).max_element() < 200.0);
+
+        let linear = flat_scene();
+        let mut rng = SmallRng::seed_from_u64(1);
+        for _ in 0..1000 {
+            let ray = Ray::new(vec3(0.0, 50.0, 0.0), random_unit_vector(&mut rng), 0.0);
+            let a = linear.hit(ray, 0.001, f32::INFINITY, &mut rng).map(|h| h.t);
+            let b = accelerated.hit(ray, 0.001, f32::INFINITY, &mut rng).map(|h| h.t);
+            assert_eq!(a, b);
+        }
+    }
+
+    #[test]
+    fn accelerated_hits_are_sublinear() {
+        enable_node_visits();
+        let accelerated = flat_scene().accelerate();
+        let mut rng = SmallRng::seed_from_u64(2);
+        let start = node_visits();
+        let rays = 1000;
+        for _ in 0..rays {
+            let ray = Ray::new(vec3(0.0, 50.0, 0.0), random_unit_vector(&mut rng), 0.0);
+            accelerated.hit(ray, 0.001, f32::INFINITY, &mut rng);
+        }
+        //the linear list tests all 1001 objects for every ray
+        let visits = node_visits().wrapping_sub(start) as f32 / rays as f32;
+        assert!(visits < 100.0, "{} nodes visited per ray", visits);
+    }
 }
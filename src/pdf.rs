@@ -0,0 +1,157 @@
+use std::f32::consts::PI;
+use bevy_math::{Vec3, vec3};
+use rand::Rng;
+use rand::rngs::SmallRng;
+use crate::obj::HittableList;
+use crate::types::Point3;
+use crate::util::{Onb, random_cosine_direction, reflect, unit_vector};
+
+//Distribution of directions over the sphere, which can be sampled and evaluated
+pub trait Pdf {
+    //Density over solid angle of `generate` returning `direction`
+    fn value(&self, direction: Vec3, rng: &mut SmallRng) -> f32;
+
+    fn generate(&self, rng: &mut SmallRng) -> Vec3;
+}
+
+//Cosine weighted hemisphere around a normal
+pub struct CosinePdf {
+    frame: Onb,
+}
+
+impl CosinePdf {
+    pub fn new(normal: Vec3) -> Self {
+        Self {
+            frame: Onb::from_w(normal),
+        }
+    }
+}
+
+impl Pdf for CosinePdf {
+    fn value(&self, direction: Vec3, _: &mut SmallRng) -> f32 {
+        let cosine = unit_vector(direction).dot(self.frame.w);
+        cosine.max(0.0) / PI
+    }
+
+    fn generate(&self, rng: &mut SmallRng) -> Vec3 {
+        self.frame.local(random_cosine_direction(rng))
+    }
+}
+
+//GGX normal distribution
+pub fn ggx_d(n_dot_h: f32, alpha2: f32) -> f32 {
+    let d = n_dot_h * n_dot_h * (alpha2 - 1.0) + 1.0;
+    alpha2 / (PI * d * d)
+}
+
+//Mirror reflections of `wi` (pointing away from the surface) around microfacet normals picked from
+//the GGX distribution, alpha2 is the squared GGX roughness
+pub struct GgxPdf {
+    frame: Onb,
+    wi: Vec3,
+    alpha2: f32,
+}
+
+impl GgxPdf {
+    pub fn new(normal: Vec3, wi: Vec3, alpha2: f32) -> Self {
+        Self {
+            frame: Onb::from_w(normal),
+            wi,
+            alpha2,
+        }
+    }
+}
+
+impl Pdf for GgxPdf {
+    fn value(&self, direction: Vec3, _: &mut SmallRng) -> f32 {
+        let wo = unit_vector(direction);
+        if wo.dot(self.frame.w) <= 0.0 {
+            return 0.0;
+        }
+        let h = unit_vector(self.wi + wo);
+        let n_dot_h = self.frame.w.dot(h).max(0.0);
+        let o_dot_h = wo.dot(h).max(1e-6);
+        ggx_d(n_dot_h, self.alpha2) * n_dot_h / (4.0 * o_dot_h)
+    }
+
+    fn generate(&self, rng: &mut SmallRng) -> Vec3 {
+        let r1: f32 = rng.gen();
+        let r2: f32 = rng.gen();
+        let phi = 2.0 * PI * r1;
+        let cos_theta = ((1.0 - r2) / (1.0 + (self.alpha2 - 1.0) * r2)).sqrt();
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        let h = self.frame.local(vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta));
+        reflect(-self.wi, h)
+    }
+}
+
+impl<P: Pdf + ?Sized> Pdf for &P {
+    fn value(&self, direction: Vec3, rng: &mut SmallRng) -> f32 {
+        (**self).value(direction, rng)
+    }
+
+    fn generate(&self, rng: &mut SmallRng) -> Vec3 {
+        (**self).generate(rng)
+    }
+}
+
+//Directions from `origin` towards the objects in a list, usually the lights
+pub struct HittablePdf<'a> {
+    objects: &'a HittableList,
+    origin: Point3,
+}
+
+impl<'a> HittablePdf<'a> {
+    pub fn new(objects: &'a HittableList, origin: Point3) -> Self {
+        Self {
+            objects,
+            origin,
+        }
+    }
+}
+
+impl Pdf for HittablePdf<'_> {
+    fn value(&self, direction: Vec3, rng: &mut SmallRng) -> f32 {
+        self.objects.pdf_value(self.origin, direction, rng)
+    }
+
+    fn generate(&self, rng: &mut SmallRng) -> Vec3 {
+        self.objects.random(self.origin, rng)
+    }
+}
+
+//Picks from `a` with probability `weight`, from `b` otherwise
+pub struct MixturePdf<A: Pdf, B: Pdf> {
+    a: A,
+    b: B,
+    weight: f32,
+}
+
+impl<A: Pdf, B: Pdf> MixturePdf<A, B> {
+    //Equal probability for both
+    pub fn new(a: A, b: B) -> Self {
+        Self::weighted(a, b, 0.5)
+    }
+
+    pub fn weighted(a: A, b: B, weight: f32) -> Self {
+        Self {
+            a,
+            b,
+            weight,
+        }
+    }
+}
+
+impl<A: Pdf, B: Pdf> Pdf for MixturePdf<A, B> {
+    fn value(&self, direction: Vec3, rng: &mut SmallRng) -> f32 {
+        self.weight * self.a.value(direction, rng) + (1.0 - self.weight) * self.b.value(direction, rng)
+    }
+
+    fn generate(&self, rng: &mut SmallRng) -> Vec3 {
+        if rng.gen::<f32>() < self.weight {
+            self.a.generate(rng)
+        } else {
+            self.b.generate(rng)
+        }
+    }
+}
@@ -1,22 +1,25 @@
 use bevy_math::Vec3;
 use crate::util::{random_vector, unit_vector};
+use rand::{Rng, SeedableRng};
+use rand::rngs::SmallRng;
 use rand::seq::SliceRandom;
 use crate::types::Point3;
 
-const POINT_COUNT: usize = 256;
+//Size of the gradient and permutation tables, the noise repeats every this many units along each axis
+pub const DEFAULT_POINT_COUNT: usize = 256;
 
-fn generate_perm() -> Vec<usize> {
-    let mut res = Vec::with_capacity(POINT_COUNT);
-    for i in 0..POINT_COUNT {
+fn generate_perm(point_count: usize, rng: &mut impl Rng) -> Vec<usize> {
+    let mut res = Vec::with_capacity(point_count);
+    for i in 0..point_count {
         res.push(i);
     }
-    let mut rng = rand::thread_rng();
-    res.shuffle(&mut rng);
+    res.shuffle(rng);
 
     res
 }
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub struct Perlin {
     vecs: Vec<Vec3>,
     perm_x: Vec<usize>,
@@ -25,14 +28,21 @@ pub struct Perlin {
 }
 
 impl Perlin {
-    pub fn new() -> Self {
-        let mut vecs = Vec::new();
-        for _ in 0..POINT_COUNT {
-            vecs.push(unit_vector(random_vector(-1.0, 1.0)))
+    pub fn new(rng: &mut impl Rng) -> Self {
+        Self::with_point_count(DEFAULT_POINT_COUNT, rng)
+    }
+
+    //Bigger tables take longer to repeat, which shows on very high frequency noise.
+    //Must be a power of two so lattice coordinates can be wrapped with a mask
+    pub fn with_point_count(point_count: usize, rng: &mut impl Rng) -> Self {
+        assert!(point_count.is_power_of_two(), "Perlin point count must be a power of two, got {}", point_count);
+        let mut vecs = Vec::with_capacity(point_count);
+        for _ in 0..point_count {
+            vecs.push(unit_vector(random_vector(-1.0, 1.0, rng)))
         }
-        let perm_x = generate_perm();
-        let perm_y = generate_perm();
-        let perm_z = generate_perm();
+        let perm_x = generate_perm(point_count, rng);
+        let perm_y = generate_perm(point_count, rng);
+        let perm_z = generate_perm(point_count, rng);
         Self {
             vecs,
             perm_x,
@@ -41,6 +51,11 @@ impl Perlin {
         }
     }
 
+    //Same seed gives the same noise, independent of any other random numbers used by the scene
+    pub fn with_seed(seed: u64) -> Self {
+        Self::new(&mut SmallRng::seed_from_u64(seed))
+    }
+
     pub fn noise(&self, p: Point3) -> f32 {
         let u = p.x - p.x.floor();
         let v = p.y - p.y.floor();
@@ -50,6 +65,8 @@ impl Perlin {
         let j = p.y.floor() as isize;
         let k = p.z.floor() as isize;
 
+        let mask = self.vecs.len() as isize - 1;
+
         let uu = u * u * (3.0 - 2.0 * u);
         let vv = v * v * (3.0 - 2.0 * v);
         let ww = w * w * (3.0 - 2.0 * w);
@@ -59,9 +76,9 @@ impl Perlin {
             for dj in 0..2 {
                 for dk in 0..2 {
                     let c = self.vecs[
-                        self.perm_x[((i + di) & 255) as usize] ^
-                        self.perm_y[((j + dj) & 255) as usize] ^
-                        self.perm_z[((k + dk) & 255) as usize]
+                        self.perm_x[((i + di) & mask) as usize] ^
+                        self.perm_y[((j + dj) & mask) as usize] ^
+                        self.perm_z[((k + dk) & mask) as usize]
                     ];
                     let i_f = di as f32;
                     let j_f = dj as f32;
@@ -95,4 +112,70 @@ impl Perlin {
 
         accum.abs()
     }
+
+    //Fractal Brownian motion, signed sum of octaves where every octave multiplies the frequency
+    //by lacunarity and the amplitude by gain
+    pub fn fbm(&self, p: Point3, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+        let mut accum = 0.0;
+        let mut p = p;
+        let mut amplitude = 1.0;
+
+        for _ in 0..octaves {
+            accum += amplitude * self.noise(p);
+            amplitude *= gain;
+            p *= lacunarity;
+        }
+
+        accum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_math::vec3;
+    use super::*;
+
+    fn points() -> impl Iterator<Item = Point3> {
+        (0..100).map(|i| vec3(i as f32 * 0.37, i as f32 * 0.11 - 3.0, i as f32 * -0.23))
+    }
+
+    #[test]
+    fn same_seed_gives_same_noise() {
+        let a = Perlin::with_seed(42);
+        let b = Perlin::with_seed(42);
+        assert!(points().all(|p| a.noise(p) == b.noise(p)));
+
+        let c = Perlin::with_seed(43);
+        assert!(points().any(|p| a.noise(p) != c.noise(p)));
+    }
+
+    //Correlation between the noise at random points spread over a large domain and `lag` units further along x
+    fn autocorrelation(perlin: &Perlin, lag: f32) -> f32 {
+        let mut rng = SmallRng::seed_from_u64(101);
+        let pairs: Vec<(f32, f32)> = (0..10000)
+            .map(|_| random_vector(-2000.0, 2000.0, &mut rng))
+            .map(|p| (perlin.noise(p), perlin.noise(p + vec3(lag, 0.0, 0.0))))
+            .collect();
+        let n = pairs.len() as f32;
+        let (mean_a, mean_b) = pairs.iter().fold((0.0, 0.0), |(a, b), &(x, y)| (a + x / n, b + y / n));
+        let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+        for &(a, b) in &pairs {
+            cov += (a - mean_a) * (b - mean_b);
+            var_a += (a - mean_a) * (a - mean_a);
+            var_b += (b - mean_b) * (b - mean_b);
+        }
+        cov / (var_a * var_b).sqrt()
+    }
+
+    #[test]
+    fn bigger_tables_repeat_less() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let default = Perlin::new(&mut rng);
+        let bigger = Perlin::with_point_count(4096, &mut rng);
+        //the default table repeats exactly every 256 units
+        let repeated = autocorrelation(&default, DEFAULT_POINT_COUNT as f32);
+        assert!(repeated > 0.99, "Got {}", repeated);
+        let bigger = autocorrelation(&bigger, DEFAULT_POINT_COUNT as f32);
+        assert!(bigger.abs() < 0.1, "Got {}", bigger);
+    }
 }
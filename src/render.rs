@@ -0,0 +1,1373 @@
+use std::cell::Cell;
+use std::error::Error;
+use std::sync::{Arc, Barrier, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+use bevy_math::{Vec3, vec3};
+use image::{ImageFormat, Rgb, Rgb32FImage, Rgba, RgbaImage};
+use minifb::{Key, KeyRepeat, Window};
+use rand::{Rng, SeedableRng};
+use rand::rngs::SmallRng;
+use crate::background::Background;
+use crate::bvh::{enable_node_visits, node_visits};
+use crate::camera::Camera;
+use crate::material::ScatterKind;
+use crate::obj::HittableList;
+use crate::pdf::{HittablePdf, Pdf};
+use crate::types::{Color, linear, luminance, Ray};
+
+pub const RES_360P: (usize, usize) = (640, 360);
+pub const RES_720P: (usize, usize) = (1280, 720);
+pub const RES_1080P: (usize, usize) = (1920, 1080);
+pub const RES_1440P: (usize, usize) = (2560, 1440);
+pub const RES_4K: (usize, usize) = (3840, 2160);
+pub const RES_8K: (usize, usize) = (7680, 4320);
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ToneMap {
+    //Clamps anything brighter than white
+    Gamma,
+    //c / (1 + c)
+    Reinhard,
+    //Narkowicz's fit of the ACES filmic curve
+    ACESFilmic,
+}
+
+impl ToneMap {
+    pub fn apply(self, color: Vec3) -> Vec3 {
+        match self {
+            ToneMap::Gamma => color,
+            ToneMap::Reinhard => color / (Vec3::ONE + color),
+            ToneMap::ACESFilmic => {
+                let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+                (color * (a * color + b)) / (color * (c * color + d) + e)
+            }
+        }
+    }
+}
+
+//Encoding of linear colors into the 8 bit output
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TransferFunction {
+    //c^(1/gamma)
+    Gamma(f32),
+    //The piecewise sRGB curve, linear near black
+    Srgb,
+}
+
+impl TransferFunction {
+    pub fn apply(self, c: f32) -> f32 {
+        match self {
+            TransferFunction::Gamma(gamma) => c.powf(1.0 / gamma),
+            TransferFunction::Srgb => {
+                if c <= 0.0031308 {
+                    12.92 * c
+                } else {
+                    1.055 * c.powf(1.0 / 2.4) - 0.055
+                }
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DebugView {
+    //Surface normal of the first hit mapped to RGB, ignoring materials and lights
+    Normals,
+    //Distance to the first hit, white at near and black at far
+    Depth { near: f32, far: f32 },
+    //Number of BVH nodes the primary ray was tested against, max and above are shown in red
+    BvhVisits { max: u32 },
+}
+
+//Pixel rectangle from (x0, y0) inclusive to (x1, y1) exclusive
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Crop {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+impl Crop {
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        x >= self.x0 && x < self.x1 && y >= self.y0 && y < self.y1
+    }
+
+    //Pixels in both rectangles, None if they don't overlap
+    pub fn intersection(&self, other: &Crop) -> Option<Crop> {
+        let res = Crop {
+            x0: self.x0.max(other.x0),
+            y0: self.y0.max(other.y0),
+            x1: self.x1.min(other.x1),
+            y1: self.y1.min(other.y1),
+        };
+        (res.x0 < res.x1 && res.y0 < res.y1).then_some(res)
+    }
+
+    fn area(&self) -> usize {
+        (self.x1 - self.x0) * (self.y1 - self.y0)
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct RenderConfig {
+    pub width: usize,
+    pub height: usize,
+    pub samples_per_pixel: u32,
+    pub max_depth: u32,
+    //Hits closer than this distance to the ray origin are ignored, so rays leaving a surface don't hit it again
+    //because of rounding errors. Scenes with very small objects need a smaller value
+    pub ray_epsilon: f32,
+    //Base seed for the per pixel RNGs, a random one is picked for every frame if unset
+    pub seed: Option<u64>,
+    //Stop sampling a pixel once the relative standard error of its mean luminance drops below this,
+    //samples_per_pixel is then only an upper bound
+    pub adaptive_threshold: Option<f32>,
+    //Paths that bounced at least this many times are randomly terminated based on how much they can
+    //still contribute, survivors are weighted up to keep the result unbiased
+    pub roulette_min_bounces: Option<u32>,
+    //Samples brighter than this are scaled down to this luminance before being accumulated, which removes
+    //fireflies at the cost of some bias. Off by default
+    pub max_sample_luminance: Option<f32>,
+    //Spread the samples of a pixel over a sqrt(n) x sqrt(n) grid, jittered inside each cell,
+    //instead of picking every position independently
+    pub stratified: bool,
+    //Multiplies the radiance before tone mapping, doubling it is one stop brighter. Only affects 8 bit output
+    pub exposure: f32,
+    pub tone_map: ToneMap,
+    pub transfer: TransferFunction,
+    //Number of threads tracing rays, defaults to one less than the number of cores
+    pub threads: Option<usize>,
+    //Replaces the path tracer with a visualization of the scene geometry
+    pub debug: Option<DebugView>,
+    //Count rays, hits and BVH nodes visited, and print a summary after rendering
+    pub stats: bool,
+    //Only sample pixels inside this rectangle, the rest of the image is left black.
+    //The camera still covers the whole image so the cropped part looks the same as in a full render
+    pub crop: Option<Crop>,
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        Self {
+            width: RES_8K.0,
+            height: RES_8K.1,
+            samples_per_pixel: 1500,
+            max_depth: 480,
+            ray_epsilon: 0.001,
+            seed: None,
+            adaptive_threshold: None,
+            roulette_min_bounces: None,
+            max_sample_luminance: None,
+            stratified: false,
+            exposure: 1.0,
+            tone_map: ToneMap::Gamma,
+            transfer: TransferFunction::Gamma(2.0),
+            threads: None,
+            debug: None,
+            stats: false,
+            crop: None,
+        }
+    }
+}
+
+//Exposure, then the tone map, then the transfer function
+pub fn to_u32(color: Vec3, samples_per_pixel: u32, config: &RenderConfig) -> u32 {
+    let scale = config.exposure / (samples_per_pixel as f32);
+    let color = config.tone_map.apply(scale * color);
+
+    let r = config.transfer.apply(color.x);
+    let g = config.transfer.apply(color.y);
+    let b = config.transfer.apply(color.z);
+
+    let red = (255.999 * r.clamp(0.0, 1.0)) as u8 as u32;
+    let green = (255.999 * g.clamp(0.0, 1.0)) as u8 as u32;
+    let blue = (255.999 * b.clamp(0.0, 1.0)) as u8 as u32;
+    (0xFF << 24) | (red << 16) | (green << 8) | blue
+}
+
+//Scales the color down so its luminance doesn't exceed max, keeping the hue
+pub fn clamp_luminance(color: Vec3, max: f32) -> Vec3 {
+    let l = luminance(color);
+    if l > max {
+        color * (max / l)
+    } else {
+        color
+    }
+}
+
+//Adaptive sampling never stops a pixel before it has this many samples
+pub const MIN_ADAPTIVE_SAMPLES: u32 = 16;
+
+#[derive(Copy, Clone, Default)]
+pub struct Pixel {
+    //Linear color, summed over all samples
+    pub sum: Vec3,
+    pub samples: u32,
+    //Running mean and sum of squared differences of the luminance (Welford's algorithm)
+    mean: f32,
+    m2: f32,
+}
+
+impl Pixel {
+    pub fn add(&mut self, color: Vec3) {
+        self.sum += color;
+        self.samples += 1;
+
+        let luminance = luminance(color);
+        let delta = luminance - self.mean;
+        self.mean += delta / self.samples as f32;
+        self.m2 += delta * (luminance - self.mean);
+    }
+
+    pub fn converged(&self, threshold: f32) -> bool {
+        if self.samples < MIN_ADAPTIVE_SAMPLES {
+            return false;
+        }
+        let n = self.samples as f32;
+        let std_error = (self.m2 / (n - 1.0) / n).sqrt();
+        std_error <= threshold * self.mean.max(1e-4)
+    }
+
+    pub fn to_u32(&self, config: &RenderConfig) -> u32 {
+        if self.samples == 0 {
+            return 0xFF << 24;
+        }
+        to_u32(self.sum, self.samples, config)
+    }
+}
+
+pub fn print_sample_stats(pixels: &[Pixel]) {
+    //pixels outside the crop have no samples
+    let sampled = || pixels.iter().filter(|p| p.samples > 0);
+    let total: u64 = sampled().map(|p| p.samples as u64).sum();
+    let min = sampled().map(|p| p.samples).min().unwrap_or(0);
+    let max = sampled().map(|p| p.samples).max().unwrap_or(0);
+    println!(
+        "Used {} samples, {:.1} per pixel on average (min {}, max {})",
+        total, total as f64 / sampled().count().max(1) as f64, min, max,
+    );
+}
+
+//Side in pixels of the square tiles render threads take at a time, smaller along the right and bottom edges
+pub const TILE_SIZE: usize = 32;
+
+//Every pixel gets its own RNG so the output doesn't depend on how pixels are split between threads,
+//progressive rendering passes get distinct RNGs so they don't repeat the same samples
+fn pixel_rng(seed: u64, pass: u32, index: usize) -> SmallRng {
+    SmallRng::seed_from_u64(seed.wrapping_add((pass as u64) << 40).wrapping_add(index as u64))
+}
+
+//Position inside the pixel of sample `index` out of `samples`, both coordinates in [0, 1)
+pub fn sample_offset(index: u32, samples: u32, stratified: bool, rng: &mut SmallRng) -> (f32, f32) {
+    let du: f32 = rng.gen();
+    let dv: f32 = rng.gen();
+    let n = (samples as f32).sqrt() as u32;
+    if !stratified || index >= n * n {
+        //samples left over after filling the grid are placed anywhere
+        return (du, dv);
+    }
+    let cell = 1.0 / n as f32;
+    (((index % n) as f32 + du) * cell, ((index / n) as f32 + dv) * cell)
+}
+
+//Totals for RenderConfig::stats
+#[derive(Copy, Clone, Default, Debug)]
+pub struct RayStats {
+    //camera rays
+    pub paths: u64,
+    //every ray traced, including the ones sent towards lights
+    pub rays: u64,
+    pub hits: u64,
+    //rays along paths, the camera ray and every bounce after it
+    pub segments: u64,
+    pub bvh_visits: u64,
+}
+
+impl RayStats {
+    pub fn add(&mut self, other: RayStats) {
+        self.paths += other.paths;
+        self.rays += other.rays;
+        self.hits += other.hits;
+        self.segments += other.segments;
+        self.bvh_visits += other.bvh_visits;
+    }
+
+    pub fn print(&self, elapsed: std::time::Duration) {
+        let rays = self.rays.max(1) as f64;
+        println!(
+            "Traced {} rays ({:.2}M/s), {:.1}% hit something, {:.2} bounces per path, {:.1} BVH nodes visited per ray",
+            self.rays, rays / elapsed.as_secs_f64() / 1e6, 100.0 * self.hits as f64 / rays,
+            self.segments as f64 / self.paths.max(1) as f64, self.bvh_visits as f64 / rays,
+        );
+    }
+}
+
+thread_local! {
+    //counted by the tracer as it goes, render_pass collects them from every thread
+    static RAY_STATS: Cell<RayStats> = Cell::new(RayStats::default());
+}
+
+fn count_path() {
+    RAY_STATS.with(|stats| {
+        let mut s = stats.get();
+        s.paths += 1;
+        stats.set(s);
+    });
+}
+
+fn count_ray(hit: bool, segment: bool) {
+    RAY_STATS.with(|stats| {
+        let mut s = stats.get();
+        s.rays += 1;
+        s.hits += hit as u64;
+        s.segments += segment as u64;
+        stats.set(s);
+    });
+}
+
+pub fn ray_color(ray: Ray, background: &Background, objs: &HittableList, lights: &HittableList, config: &RenderConfig, rng: &mut SmallRng) -> Color {
+    if let Some(view) = config.debug {
+        return debug_color(view, ray, background, objs, config, rng);
+    }
+    trace(ray, background, objs, lights, config, rng).extend(1.0)
+}
+
+//Google's polynomial approximation of the turbo colormap, x in [0, 1]
+fn turbo(x: f32) -> Vec3 {
+    let x = x.clamp(0.0, 1.0);
+    let r = 0.135_721 + x * (4.615_393 + x * (-42.660_32 + x * (132.131_1 + x * (-152.942_4 + x * 59.286_38))));
+    let g = 0.091_402_6 + x * (2.194_188 + x * (4.842_967 + x * (-14.185_03 + x * (4.277_299 + x * 2.829_566))));
+    let b = 0.106_673_3 + x * (12.641_95 + x * (-60.582_05 + x * (110.362_8 + x * (-89.903_11 + x * 27.348_25))));
+    vec3(r, g, b)
+}
+
+//t_min for `ray` that skips hits closer than the epsilon distance, whatever the length of its direction
+fn t_min(ray: &Ray, config: &RenderConfig) -> f32 {
+    config.ray_epsilon / ray.direction.length()
+}
+
+fn debug_color(view: DebugView, ray: Ray, background: &Background, objs: &HittableList, config: &RenderConfig, rng: &mut SmallRng) -> Color {
+    //the counter keeps going for --stats, only this ray's share is shown
+    let visits = node_visits();
+    let hr = objs.hit(ray, t_min(&ray, config), f32::INFINITY, rng);
+    if config.stats {
+        count_path();
+        count_ray(hr.is_some(), true);
+    }
+    if let DebugView::BvhVisits { max } = view {
+        return turbo(node_visits().wrapping_sub(visits) as f32 / max as f32).extend(1.0);
+    }
+    let hr = match hr {
+        Some(hr) => hr,
+        None => return background.color(ray.direction),
+    };
+    match view {
+        DebugView::Normals => (0.5 * (hr.normal + Vec3::ONE)).extend(1.0),
+        DebugView::Depth { near, far } => {
+            let depth = hr.t * ray.direction.length();
+            Vec3::splat(1.0 - ((depth - near) / (far - near)).clamp(0.0, 1.0)).extend(1.0)
+        }
+        DebugView::BvhVisits { .. } => unreachable!(),
+    }
+}
+
+//Power heuristic for multiple importance sampling, weight of the strategy with density `a`
+fn mis_weight(a: f32, b: f32) -> f32 {
+    let a2 = a * a;
+    a2 / (a2 + b * b)
+}
+
+//Follows a single path, `throughput` is the product of all attenuations along it so far,
+//`bsdf_pdf` is the density the previous diffuse bounce picked `ray` with (None after specular bounces),
+//`medium` is the absorption of whatever the ray is traveling through (zero outside of any object)
+fn trace(ray: Ray, background: &Background, objs: &HittableList, lights: &HittableList, config: &RenderConfig, rng: &mut SmallRng) -> Vec3 {
+    let mut ray = ray;
+    let mut radiance = Vec3::ZERO;
+    let mut throughput = Vec3::ONE;
+    let mut bsdf_pdf: Option<f32> = None;
+    let mut medium = Vec3::ZERO;
+    //environment maps are sampled directly like the lights
+    let environment = match background {
+        Background::Environment(map) => Some(map),
+        _ => None,
+    };
+
+    if config.stats {
+        count_path();
+    }
+
+    for bounce in 0..config.max_depth {
+        let hr = objs.hit(ray, t_min(&ray, config), f32::INFINITY, rng);
+        if config.stats {
+            count_ray(hr.is_some(), true);
+        }
+        let hr = match hr {
+            Some(hr) => hr,
+            None => {
+                let mut le = linear(background.color(ray.direction));
+                if let (Some(pdf), Some(map)) = (bsdf_pdf, environment) {
+                    le *= mis_weight(pdf, map.pdf_value(ray.direction));
+                }
+                radiance += throughput * le;
+                break;
+            }
+        };
+
+        //Beer-Lambert absorption over the segment that just got traced
+        throughput *= (-medium * hr.t * ray.direction.length()).exp();
+
+        let mut emitted = linear(hr.material.emitted(&hr));
+        if let Some(pdf) = bsdf_pdf {
+            //this light was also reachable through light sampling at the previous bounce
+            let light_pdf = lights.pdf_value(ray.origin, ray.direction, rng);
+            emitted *= mis_weight(pdf, light_pdf);
+        }
+        radiance += throughput * emitted;
+
+        let record = match hr.material.scatter(&ray, &hr, rng) {
+            Some(record) => record,
+            None => break,
+        };
+        let mut attenuation = linear(record.attenuation);
+        //density the bounce direction was picked with, if the material can be sampled towards lights
+        let mut scattered_pdf = None;
+        let scattered = match &record.kind {
+            ScatterKind::Specular(scattered) => *scattered,
+            ScatterKind::Diffuse { pdf } | ScatterKind::Lobe { pdf, .. } => {
+                let bsdf = match &record.kind {
+                    ScatterKind::Lobe { bsdf, .. } => Some(bsdf),
+                    _ => None,
+                };
+                //reflectance towards a direction picked with density `pdf`, relative to the attenuation.
+                //Diffuse densities are proportional to the BSDF, so it's always one for them
+                let lobe = |direction: Vec3, pdf: f32| bsdf.map_or(Vec3::ONE, |bsdf| bsdf(direction) / pdf);
+
+                let scattered = Ray::new(hr.position, pdf.generate(rng), ray.time);
+                let sampled_pdf = pdf.value(scattered.direction, rng);
+                if !lights.is_empty() || environment.is_some() {
+                    scattered_pdf = Some(sampled_pdf);
+                }
+                if !lights.is_empty() {
+                    let light = HittablePdf::new(lights, hr.position);
+                    let to_light = Ray::new(hr.position, light.generate(rng), ray.time);
+                    let light_pdf = light.value(to_light.direction, rng);
+                    let pdf = pdf.value(to_light.direction, rng);
+                    if light_pdf > 0.0 && pdf > 0.0 {
+                        let lr = objs.hit(to_light, t_min(&to_light, config), f32::INFINITY, rng);
+                        if config.stats {
+                            count_ray(lr.is_some(), false);
+                        }
+                        if let Some(lr) = lr {
+                            let le = linear(lr.material.emitted(&lr));
+                            radiance += throughput * attenuation * lobe(to_light.direction, pdf) * le * (pdf / light_pdf) * mis_weight(light_pdf, pdf);
+                        }
+                    }
+                }
+                if let Some(map) = environment {
+                    let to_sky = Ray::new(hr.position, map.random(rng), ray.time);
+                    let sky_pdf = map.pdf_value(to_sky.direction);
+                    let pdf = pdf.value(to_sky.direction, rng);
+                    if sky_pdf > 0.0 && pdf > 0.0 {
+                        let blocked = objs.hit(to_sky, t_min(&to_sky, config), f32::INFINITY, rng).is_some();
+                        if config.stats {
+                            count_ray(blocked, false);
+                        }
+                        if !blocked {
+                            let le = linear(map.color(to_sky.direction));
+                            radiance += throughput * attenuation * lobe(to_sky.direction, pdf) * le * (pdf / sky_pdf) * mis_weight(sky_pdf, pdf);
+                        }
+                    }
+                }
+                if bsdf.is_some() {
+                    //a direction the material never reflects to, like one below the surface
+                    if sampled_pdf <= 0.0 {
+                        break;
+                    }
+                    attenuation *= lobe(scattered.direction, sampled_pdf);
+                }
+                scattered
+            }
+        };
+
+        throughput *= attenuation;
+        if config.roulette_min_bounces.is_some_and(|min| bounce >= min) {
+            let survival = throughput.max_element().min(1.0);
+            if rng.gen::<f32>() >= survival {
+                break;
+            }
+            throughput /= survival;
+        }
+
+        bsdf_pdf = scattered_pdf;
+        //rays going through the surface enter the object, or leave it if they hit it from the inside
+        if scattered.direction.dot(hr.normal) < 0.0 {
+            medium = if hr.front_face {
+                hr.material.absorption()
+            } else {
+                Vec3::ZERO
+            };
+        }
+        ray = scattered;
+    }
+
+    radiance
+}
+
+//How often progress is printed
+const PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+//Prints the fraction of pixels done and an estimate of the time left to stderr, at most every PROGRESS_INTERVAL
+struct Progress {
+    start: std::time::Instant,
+    total: usize,
+    done: AtomicUsize,
+    last_report: Mutex<std::time::Instant>,
+}
+
+impl Progress {
+    fn new(total: usize) -> Self {
+        let start = std::time::Instant::now();
+        Self {
+            start,
+            total,
+            done: AtomicUsize::new(0),
+            last_report: Mutex::new(start),
+        }
+    }
+
+    fn add(&self, pixels: usize) {
+        let done = self.done.fetch_add(pixels, Ordering::Relaxed) + pixels;
+        //another thread is already reporting
+        let mut last_report = match self.last_report.try_lock() {
+            Ok(last_report) => last_report,
+            Err(_) => return,
+        };
+        if last_report.elapsed() < PROGRESS_INTERVAL {
+            return;
+        }
+        *last_report = std::time::Instant::now();
+
+        let elapsed = self.start.elapsed().as_secs_f32();
+        let left = elapsed / done as f32 * (self.total - done) as f32;
+        eprint!("\r{:5.1}% done, about {:.0}s left   ", 100.0 * done as f32 / self.total as f32, left);
+    }
+
+    fn finish(&self) {
+        //only clear the line if something was printed
+        if self.start.elapsed() >= PROGRESS_INTERVAL {
+            eprintln!();
+        }
+    }
+}
+
+//BVH node visits are only counted when something shows them
+fn count_node_visits(config: &RenderConfig) {
+    if config.stats || matches!(config.debug, Some(DebugView::BvhVisits { .. })) {
+        enable_node_visits();
+    }
+}
+
+fn thread_count(config: &RenderConfig) -> usize {
+    config.threads.unwrap_or_else(|| {
+        //leave a core free for the window
+        let available = std::thread::available_parallelism().unwrap().get();
+        (available - 1).max(1)
+    })
+}
+
+//config.crop clamped to the image, the whole image if unset
+fn crop_window(config: &RenderConfig) -> Crop {
+    let RenderConfig { width, height, .. } = *config;
+    config.crop.map_or(Crop { x0: 0, y0: 0, x1: width, y1: height }, |c| Crop {
+        x1: c.x1.min(width),
+        y1: c.y1.min(height),
+        ..c
+    })
+}
+
+//Every tile of the image, row by row
+fn tiles(width: usize, height: usize) -> impl Iterator<Item = Crop> {
+    (0..height).step_by(TILE_SIZE).flat_map(move |y0| (0..width).step_by(TILE_SIZE).map(move |x0| Crop {
+        x0,
+        y0,
+        x1: (x0 + TILE_SIZE).min(width),
+        y1: (y0 + TILE_SIZE).min(height),
+    }))
+}
+
+//Splits the pixels of a whole image into the rows of each tile, in the same order as tiles()
+fn split_tiles(pixels: &mut [Pixel], width: usize, height: usize) -> Vec<(Crop, Vec<&mut [Pixel]>)> {
+    let mut res: Vec<_> = tiles(width, height).map(|tile| (tile, Vec::new())).collect();
+    let columns = width.div_ceil(TILE_SIZE);
+    for (y, row) in pixels.chunks_mut(width).enumerate() {
+        for (column, run) in row.chunks_mut(TILE_SIZE).enumerate() {
+            res[y / TILE_SIZE * columns + column].1.push(run);
+        }
+    }
+    res
+}
+
+
+//What a pass traces and how many samples it adds to every pixel
+#[derive(Copy, Clone)]
+pub struct RenderPass<'a> {
+    pub camera: &'a Camera,
+    pub objs: &'a HittableList,
+    pub lights: &'a HittableList,
+    pub background: &'a Background,
+    pub config: &'a RenderConfig,
+    //base seed of the pixel RNGs, passes with different indices get different samples
+    pub seed: u64,
+    pub index: u32,
+    pub samples: u32,
+}
+
+//Traces up to `samples` more samples for the pixels of `tile` inside the crop, fewer if adaptive sampling
+//considers them converged. `rows` are the rows of the tile from the top. Returns false if `stop` returned true before it was done
+fn render_tile<'p>(pass: &RenderPass, crop: Crop, tile: Crop, rows: impl Iterator<Item = &'p mut [Pixel]>, stop: &impl Fn() -> bool) -> bool {
+    let RenderPass { camera, objs, lights, background, config, seed, index, samples } = *pass;
+    let RenderConfig { width, height, .. } = *config;
+    for ((x, y), pixel) in rows.zip(tile.y0..).flat_map(|(row, y)| (tile.x0..).map(move |x| (x, y)).zip(row.iter_mut())) {
+        if stop() {
+            return false;
+        }
+        if !crop.contains(x, y) {
+            continue;
+        }
+        let idx = y * width + x;
+        let mut rng = pixel_rng(seed, index, idx);
+        let visits = node_visits();
+
+        for i in 0..samples {
+            if config.adaptive_threshold.is_some_and(|t| pixel.converged(t)) {
+                break;
+            }
+            let (du, dv) = sample_offset(i, samples, config.stratified, &mut rng);
+            let u = (x as f32 + du) / (width - 1) as f32;
+            let v = 1.0 - (y as f32 + dv) / (height - 1) as f32;
+            let r = camera.ray(u, v, &mut rng);
+            let c = ray_color(r, background, objs, lights, config, &mut rng);
+            let mut c = linear(c);
+            if let Some(max) = config.max_sample_luminance {
+                c = clamp_luminance(c, max);
+            }
+            pixel.add(c);
+        }
+        if config.stats {
+            let visits = node_visits().wrapping_sub(visits);
+            RAY_STATS.with(|stats| {
+                let mut s = stats.get();
+                s.bvh_visits += visits as u64;
+                stats.set(s);
+            });
+        }
+    }
+    true
+}
+
+//Traces up to `samples` more samples for every pixel, fewer if adaptive sampling considers it converged
+pub fn render_pass(pass: &RenderPass, accum: &mut [Pixel]) -> RayStats {
+    let config = pass.config;
+    let RenderConfig { width, height, .. } = *config;
+    assert_eq!(accum.len(), width * height);
+    count_node_visits(config);
+    let threads = thread_count(config);
+    let crop = crop_window(config);
+    let crop_width = crop.x1.saturating_sub(crop.x0);
+    let crop_height = crop.y1.saturating_sub(crop.y0);
+
+    //threads take the next tile from here, so each one writes to a different part of accum.
+    //Tiles outside the crop are never handed out
+    let tiles = Mutex::new(split_tiles(accum, width, height).into_iter().filter(|(tile, _)| tile.intersection(&crop).is_some()));
+    let progress = Progress::new(crop_width * crop_height);
+    let stats = Mutex::new(RayStats::default());
+
+    let work = || {
+        loop {
+            let next = tiles.lock().unwrap().next();
+            let (tile, rows) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            render_tile(pass, crop, tile, rows.into_iter(), &|| false);
+            progress.add(tile.intersection(&crop).map_or(0, |c| c.area()));
+            if config.stats {
+                stats.lock().unwrap().add(RAY_STATS.with(|s| s.take()));
+            }
+        }
+    };
+
+    if threads == 1 {
+        work();
+    } else {
+        std::thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(work);
+            }
+        });
+    }
+    progress.finish();
+    stats.into_inner().unwrap()
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum OutputFormat {
+    //8 bit gamma corrected image, the format is picked from the file extension (.png, .ppm, ...)
+    Ldr,
+    //32 bit float OpenEXR with the linear, unclamped radiance
+    Exr,
+}
+
+fn render_accum(camera: &Camera, objs: &HittableList, lights: &HittableList, background: &Background, config: &RenderConfig) -> Vec<Pixel> {
+    let seed = config.seed.unwrap_or_else(rand::random);
+    let mut accum = vec![Pixel::default(); config.width * config.height];
+    let start = std::time::Instant::now();
+    let pass = RenderPass { camera, objs, lights, background, config, seed, index: 0, samples: config.samples_per_pixel };
+    let stats = render_pass(&pass, &mut accum);
+    if config.adaptive_threshold.is_some() {
+        print_sample_stats(&accum);
+    }
+    if config.stats {
+        stats.print(start.elapsed());
+    }
+    accum
+}
+
+pub fn render_to_image(camera: &Camera, objs: &HittableList, lights: &HittableList, background: &Background, config: &RenderConfig) -> RgbaImage {
+    let accum = render_accum(camera, objs, lights, background, config);
+    let buffer: Vec<u32> = accum.iter().map(|p| p.to_u32(config)).collect();
+    to_image(&buffer, config.width, config.height)
+}
+
+//Unpacks pixels in the 0xAARRGGBB format produced by to_u32 and used by the window
+pub fn to_image(buffer: &[u32], width: usize, height: usize) -> RgbaImage {
+    assert_eq!(buffer.len(), width * height);
+    RgbaImage::from_fn(width as _, height as _, |x, y| {
+        let c = buffer[y as usize * width + x as usize];
+        Rgba([(c >> 16) as u8, (c >> 8) as u8, c as u8, (c >> 24) as u8])
+    })
+}
+
+pub fn render_to_linear_image(camera: &Camera, objs: &HittableList, lights: &HittableList, background: &Background, config: &RenderConfig) -> Rgb32FImage {
+    let accum = render_accum(camera, objs, lights, background, config);
+
+    Rgb32FImage::from_fn(config.width as _, config.height as _, |x, y| {
+        let pixel = &accum[y as usize * config.width + x as usize];
+        let c = pixel.sum / pixel.samples.max(1) as f32;
+        Rgb([c.x, c.y, c.z])
+    })
+}
+
+//Number of samples per pixel traced between window updates
+const SAMPLES_PER_BATCH: u32 = 8;
+
+//Factor `+`/`-` change the exposure by while rendering in a window, one stop
+const EXPOSURE_STEP: f32 = 2.0;
+
+//Distance moved and angle turned (in radians) per window update while a key is held
+const MOVE_STEP: f32 = 0.05;
+const TURN_STEP: f32 = 0.02;
+
+//WASD moves, Q/E go down/up and the arrow keys look around. None if no key is held
+fn camera_controls(window: &Window, camera: Camera) -> Option<Camera> {
+    let key = |k| if window.is_key_down(k) { 1.0 } else { 0.0 };
+    let forward = key(Key::W) - key(Key::S);
+    let right = key(Key::D) - key(Key::A);
+    let up = key(Key::E) - key(Key::Q);
+    let yaw = key(Key::Left) - key(Key::Right);
+    let pitch = key(Key::Up) - key(Key::Down);
+    if forward == 0.0 && right == 0.0 && up == 0.0 && yaw == 0.0 && pitch == 0.0 {
+        return None;
+    }
+
+    //moving forward stays level, like walking
+    let ahead = (camera.forward() * vec3(1.0, 0.0, 1.0)).normalize_or_zero();
+    let offset = ahead * forward + camera.right() * right + Vec3::Y * up;
+    Some(camera.translated(offset * MOVE_STEP).rotated(yaw * TURN_STEP, pitch * TURN_STEP))
+}
+
+struct Scene {
+    objs: HittableList,
+    lights: HittableList,
+    background: Background,
+}
+
+//What the window wants the render thread to work on
+#[derive(Clone)]
+enum Request {
+    //boxed since it's much larger than Quit
+    Render(Box<(Camera, RenderConfig)>),
+    Quit,
+}
+
+struct Control {
+    //latest request and its generation, which goes up by one for every new request
+    request: Mutex<(u64, Request)>,
+    changed: Condvar,
+    //generation of the latest request, checked for every pixel so outdated passes stop quickly
+    generation: AtomicU64,
+}
+
+impl Control {
+    fn new(request: Request) -> Self {
+        Self {
+            request: Mutex::new((1, request)),
+            changed: Condvar::new(),
+            generation: AtomicU64::new(1),
+        }
+    }
+
+    //Replaces the current request, returns its generation
+    fn send(&self, request: Request) -> u64 {
+        let mut current = self.request.lock().unwrap();
+        let generation = current.0 + 1;
+        *current = (generation, request);
+        self.generation.store(generation, Ordering::Relaxed);
+        self.changed.notify_one();
+        generation
+    }
+
+    fn outdated(&self, generation: u64) -> bool {
+        self.generation.load(Ordering::Relaxed) != generation
+    }
+}
+
+//Accumulated samples handed from the render thread to the window, with the generation of the request they belong to
+type SwapChain = Arc<Mutex<Option<(u64, Vec<Pixel>)>>>;
+
+//The pass the workers of the render thread are tracing
+struct Pass {
+    generation: u64,
+    camera: Camera,
+    config: RenderConfig,
+    seed: u64,
+    index: u32,
+    samples: u32,
+    //accumulated samples of every tile, row by row
+    tiles: Vec<(Crop, Mutex<Vec<Pixel>>)>,
+}
+
+//Copies the samples of every tile back into a whole image
+fn merge_tiles(tiles: &[(Crop, Mutex<Vec<Pixel>>)], width: usize, height: usize) -> Vec<Pixel> {
+    let mut res = vec![Pixel::default(); width * height];
+    for (tile, pixels) in tiles {
+        let pixels = pixels.lock().unwrap();
+        let tile_width = tile.x1 - tile.x0;
+        for (y, row) in (tile.y0..).zip(pixels.chunks(tile_width)) {
+            res[y * width + tile.x0..][..tile_width].copy_from_slice(row);
+        }
+    }
+    res
+}
+
+//Renders progressively on a new thread until it gets Request::Quit, handing over the samples after every batch.
+//Its workers are spawned once and reused for every pass, a new request abandons the current one
+fn spawn_renderer(scene: Arc<Scene>, threads: usize, control: Arc<Control>, swap_chain: SwapChain) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let current: RwLock<Option<Pass>> = RwLock::new(None);
+        let next_tile = AtomicUsize::new(0);
+        let stats = Mutex::new(RayStats::default());
+        //workers wait on `start` until a pass is ready (or None to quit), and on `end` until all of them finished it
+        let start = Barrier::new(threads + 1);
+        let end = Barrier::new(threads + 1);
+
+        std::thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| loop {
+                    start.wait();
+                    let current = current.read().unwrap();
+                    let pass = match current.as_ref() {
+                        Some(pass) => pass,
+                        None => break,
+                    };
+                    let crop = crop_window(&pass.config);
+                    let stop = || control.outdated(pass.generation);
+                    while let Some((tile, pixels)) = pass.tiles.get(next_tile.fetch_add(1, Ordering::Relaxed)) {
+                        if tile.intersection(&crop).is_none() {
+                            continue;
+                        }
+                        let mut pixels = pixels.lock().unwrap();
+                        let render = RenderPass {
+                            camera: &pass.camera,
+                            objs: &scene.objs,
+                            lights: &scene.lights,
+                            background: &scene.background,
+                            config: &pass.config,
+                            seed: pass.seed,
+                            index: pass.index,
+                            samples: pass.samples,
+                        };
+                        let done = render_tile(&render, crop, *tile, pixels.chunks_mut(tile.x1 - tile.x0), &stop);
+                        if pass.config.stats {
+                            stats.lock().unwrap().add(RAY_STATS.with(|s| s.take()));
+                        }
+                        if !done {
+                            break;
+                        }
+                    }
+                    drop(current);
+                    end.wait();
+                });
+            }
+
+            let mut handled = 0;
+            loop {
+                let (generation, request) = {
+                    let mut request = control.request.lock().unwrap();
+                    while request.0 == handled {
+                        request = control.changed.wait(request).unwrap();
+                    }
+                    request.clone()
+                };
+                handled = generation;
+                let (camera, config) = match request {
+                    Request::Render(render) => *render,
+                    Request::Quit => break,
+                };
+                count_node_visits(&config);
+
+                let render_start = std::time::Instant::now();
+                let tiles = tiles(config.width, config.height)
+                    .map(|tile| (tile, Mutex::new(vec![Pixel::default(); tile.area()])))
+                    .collect();
+                *current.write().unwrap() = Some(Pass {
+                    generation,
+                    camera,
+                    config,
+                    seed: config.seed.unwrap_or_else(rand::random),
+                    index: 0,
+                    samples: 0,
+                    tiles,
+                });
+                *stats.lock().unwrap() = RayStats::default();
+
+                let mut pixels = Vec::new();
+                let mut samples = 0;
+                let mut pass = 0;
+                while samples < config.samples_per_pixel {
+                    //a quick first pass so there's something to see right after moving the camera
+                    let batch = if pass == 0 { 1 } else { SAMPLES_PER_BATCH };
+                    let batch = batch.min(config.samples_per_pixel - samples);
+                    if let Some(current) = current.write().unwrap().as_mut() {
+                        current.index = pass;
+                        current.samples = batch;
+                    }
+                    next_tile.store(0, Ordering::Relaxed);
+                    start.wait();
+                    end.wait();
+                    samples += batch;
+                    pass += 1;
+
+                    if control.outdated(generation) {
+                        break;
+                    }
+                    pixels = merge_tiles(&current.read().unwrap().as_ref().unwrap().tiles, config.width, config.height);
+                    *swap_chain.lock().unwrap() = Some((generation, pixels.clone()));
+                }
+                if control.outdated(generation) {
+                    continue;
+                }
+                let elapsed = render_start.elapsed();
+                println!("Rendered {} samples per pixel in {:?}", samples, elapsed);
+                if config.adaptive_threshold.is_some() {
+                    print_sample_stats(&pixels);
+                }
+                if config.stats {
+                    stats.lock().unwrap().print(elapsed);
+                }
+            }
+            *current.write().unwrap() = None;
+            start.wait();
+        });
+    })
+}
+
+//Saves what the window currently shows as screenshot-<unix time>.png in the working directory
+fn save_screenshot(buffer: &[u32], width: usize, height: usize) {
+    let time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+    let path = format!("screenshot-{}.png", time.as_secs());
+    match to_image(buffer, width, height).save(&path) {
+        Ok(()) => println!("Saved {}", path),
+        Err(e) => eprintln!("Failed to save {}: {}", path, e),
+    }
+}
+
+//Restarts the render whenever the window is resized or the camera moves
+pub fn render_window(mut window: Window, camera: Camera, objs: HittableList, lights: HittableList, background: Background, config: RenderConfig) {
+    let scene = Arc::new(Scene {
+        objs: objs.accelerate(),
+        lights,
+        background,
+    });
+    let mut config = config;
+    let mut camera = camera;
+
+    //the render thread hands over the accumulated samples, which get encoded here so exposure
+    //changes apply without waiting for the next pass
+    let swap_chain: SwapChain = Arc::new(Mutex::new(None));
+    let control = Arc::new(Control::new(Request::Render(Box::new((camera, config)))));
+    //results for older requests are dropped
+    let mut generation = 1;
+    let render = spawn_renderer(scene, thread_count(&config), control.clone(), swap_chain.clone());
+
+    let mut pixels = vec![Pixel::default(); config.width * config.height];
+    //always the size of `buffer`, which lags behind the window until the new render produces something
+    let mut buffer_size = (config.width, config.height);
+    let mut buffer = vec![0; config.width * config.height];
+    window.limit_update_rate(Some(std::time::Duration::from_millis(16)));
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        let mut restart = false;
+        let (width, height) = window.get_size();
+        if (width, height) != (config.width, config.height) && width >= 2 && height >= 2 {
+            config.width = width;
+            config.height = height;
+            camera = camera.with_aspect_ratio(width as f32 / height as f32);
+            restart = true;
+        }
+        if let Some(moved) = camera_controls(&window, camera) {
+            camera = moved;
+            restart = true;
+        }
+        if restart {
+            //workers check the generation for every pixel, so this doesn't wait for the whole pass
+            generation = control.send(Request::Render(Box::new((camera, config))));
+        }
+
+        let mut changed = false;
+        if window.is_key_pressed(Key::Equal, KeyRepeat::Yes) || window.is_key_pressed(Key::NumPadPlus, KeyRepeat::Yes) {
+            config.exposure *= EXPOSURE_STEP;
+            changed = true;
+        }
+        if window.is_key_pressed(Key::Minus, KeyRepeat::Yes) || window.is_key_pressed(Key::NumPadMinus, KeyRepeat::Yes) {
+            config.exposure /= EXPOSURE_STEP;
+            changed = true;
+        }
+        if changed {
+            println!("Exposure {:+.0} EV", config.exposure.log2());
+        }
+        if let Some((latest_generation, latest)) = swap_chain.lock().unwrap().take() {
+            if latest_generation == generation {
+                pixels = latest;
+                buffer_size = (config.width, config.height);
+                changed = true;
+            }
+        }
+        if changed {
+            buffer = pixels.iter().map(|p| p.to_u32(&config)).collect();
+        }
+        if window.is_key_pressed(Key::P, KeyRepeat::No) {
+            save_screenshot(&buffer, buffer_size.0, buffer_size.1);
+        }
+        window
+            .update_with_buffer(&buffer, buffer_size.0, buffer_size.1)
+            .unwrap();
+    }
+    control.send(Request::Quit);
+    render.join().unwrap();
+}
+
+//Renders `frames` frames to directory/frame_0000.png, frame_0001.png, ... The scene is rebuilt for every
+//frame by build_scene, which gets the time in [0, 1) and returns the camera, objects and lights
+pub fn render_animation(directory: &str, frames: u32, background: Background, config: RenderConfig, build_scene: impl Fn(f32) -> (Camera, HittableList, HittableList)) -> Result<(), Box<dyn Error>> {
+    std::fs::create_dir_all(directory).map_err(|e| format!("Failed to create {}: {}", directory, e))?;
+    let start = std::time::Instant::now();
+    for frame in 0..frames {
+        let (camera, objs, lights) = build_scene(frame as f32 / frames as f32);
+        let path = format!("{}/frame_{:04}.png", directory, frame);
+        println!("Rendering frame {} of {}", frame + 1, frames);
+        render_file(&path, OutputFormat::Ldr, camera, objs, lights, background.clone(), config)?;
+    }
+    println!("Rendered {} frames in {:?}", frames, start.elapsed());
+    Ok(())
+}
+
+pub fn render_file(path: &str, format: OutputFormat, camera: Camera, objs: HittableList, lights: HittableList, background: Background, config: RenderConfig) -> Result<(), Box<dyn Error>> {
+    let objs = objs.accelerate();
+    let start = std::time::Instant::now();
+    let res = match format {
+        OutputFormat::Ldr => {
+            let image = render_to_image(&camera, &objs, &lights, &background, &config);
+            println!("Rendered frame in {:?}", start.elapsed());
+            image.save(path)
+        }
+        OutputFormat::Exr => {
+            let image = render_to_linear_image(&camera, &objs, &lights, &background, &config);
+            println!("Rendered frame in {:?}", start.elapsed());
+            image.save_with_format(path, ImageFormat::OpenExr)
+        }
+    };
+    res.map_err(|e| format!("Failed to save {}: {}", path, e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use bevy_math::vec4;
+    use image::{GenericImageView, ImageOutputFormat};
+    use crate::material::{Dielectric, DiffuseLight, Lambertian, Material, Metal, Pbr};
+    use crate::obj::Sphere;
+    use crate::texture::SolidColor;
+    use crate::util::random_unit_vector;
+    use super::*;
+
+    //Gray sphere under a sky, small enough to render in a few milliseconds
+    fn scene() -> (Camera, HittableList, Background) {
+        let camera = Camera::new(vec3(0.0, 0.0, 3.0), Vec3::ZERO, Vec3::Y, 40.0, 1.0, 0.0, 3.0);
+        let mut objs = HittableList::new();
+        objs.add(Sphere::new(Vec3::ZERO, 1.0, Lambertian::color(vec4(0.5, 0.5, 0.5, 1.0))));
+        (camera, objs, Background::sky())
+    }
+
+    fn config(width: usize, height: usize, samples_per_pixel: u32) -> RenderConfig {
+        RenderConfig {
+            width,
+            height,
+            samples_per_pixel,
+            max_depth: 8,
+            seed: Some(1),
+            threads: Some(1),
+            ..RenderConfig::default()
+        }
+    }
+
+    #[test]
+    fn renders_valid_png() {
+        let (camera, objs, background) = scene();
+        let config = config(4, 4, 1);
+        let image = render_to_image(&camera, &objs, &HittableList::new(), &background, &config);
+
+        let mut bytes = Vec::new();
+        image.write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::Png).unwrap();
+        assert!(!bytes.is_empty());
+        let decoded = image::load_from_memory(&bytes).unwrap();
+        assert_eq!(decoded.dimensions(), (4, 4));
+    }
+
+    #[test]
+    fn tiles_cover_every_pixel_once() {
+        let (camera, objs, background) = scene();
+        //not multiples of the tile size, so the last row and column of tiles are partial
+        let (width, height) = (2 * TILE_SIZE + 7, 3 * TILE_SIZE + 5);
+        let config = RenderConfig {
+            threads: Some(4),
+            ..config(width, height, 1)
+        };
+        let lights = HittableList::new();
+        let pass = |config| RenderPass { camera: &camera, objs: &objs, lights: &lights, background: &background, config, seed: 1, index: 0, samples: 1 };
+        let mut accum = vec![Pixel::default(); width * height];
+        render_pass(&pass(&config), &mut accum);
+        //a single sample per pass, so anything else means a pixel was skipped or rendered twice
+        assert!(accum.iter().all(|p| p.samples == 1));
+
+        let crop = Crop { x0: TILE_SIZE - 2, y0: TILE_SIZE - 3, x1: TILE_SIZE + 5, y1: 2 * TILE_SIZE + 1 };
+        let config = RenderConfig {
+            crop: Some(crop),
+            ..config
+        };
+        let mut accum = vec![Pixel::default(); width * height];
+        render_pass(&pass(&config), &mut accum);
+        for (i, p) in accum.iter().enumerate() {
+            assert_eq!(p.samples, crop.contains(i % width, i / width) as u32);
+        }
+
+        //the window renders into a buffer per tile, which must line up with the whole image again
+        let tiles: Vec<_> = split_tiles(&mut accum, width, height).into_iter()
+            .map(|(tile, rows)| (tile, Mutex::new(rows.concat())))
+            .collect();
+        let merged = merge_tiles(&tiles, width, height);
+        assert!(merged.iter().zip(&accum).all(|(a, b)| a.samples == b.samples));
+    }
+
+    #[test]
+    fn crop_only_touches_intersecting_tiles() {
+        let (width, height) = (4 * TILE_SIZE, 3 * TILE_SIZE);
+        let inside = |crop: Crop| tiles(width, height).filter(|tile| tile.intersection(&crop).is_some()).count();
+        //a few rows across the whole image, and a small region at the corner of four tiles
+        assert_eq!(inside(Crop { x0: 0, y0: TILE_SIZE + 1, x1: width, y1: TILE_SIZE + 5 }), 4);
+        assert_eq!(inside(Crop { x0: TILE_SIZE - 1, y0: TILE_SIZE - 1, x1: TILE_SIZE + 1, y1: TILE_SIZE + 1 }), 4);
+        assert_eq!(inside(Crop { x0: 1, y0: 1, x1: 2, y1: 2 }), 1);
+        //tiles that only share an edge with the crop don't overlap it
+        assert_eq!(inside(Crop { x0: 0, y0: 0, x1: TILE_SIZE, y1: TILE_SIZE }), 1);
+    }
+
+    #[test]
+    fn adaptive_sampling_spends_samples_on_edges() {
+        //a light on a black background, both flat, so only pixels covering its silhouette have any variance
+        let camera = Camera::new(vec3(0.0, 0.0, 3.0), Vec3::ZERO, Vec3::Y, 40.0, 1.0, 0.0, 3.0);
+        let mut objs = HittableList::new();
+        objs.add(Sphere::new(Vec3::ZERO, 0.5, DiffuseLight::color(vec4(1.0, 1.0, 1.0, 1.0))));
+        let background = Background::Solid(vec4(0.0, 0.0, 0.0, 1.0));
+        let config = RenderConfig {
+            adaptive_threshold: Some(0.01),
+            ..config(17, 17, 256)
+        };
+        let accum = render_accum(&camera, &objs, &HittableList::new(), &background, &config);
+
+        let samples = |x: usize, y: usize| accum[y * 17 + x].samples;
+        assert_eq!(samples(0, 0), MIN_ADAPTIVE_SAMPLES);
+        assert_eq!(samples(8, 8), MIN_ADAPTIVE_SAMPLES);
+        let edge = (0..17).map(|x| samples(x, 8)).max().unwrap();
+        assert_eq!(edge, 256);
+    }
+
+    #[test]
+    fn clamps_fireflies() {
+        let firefly = vec3(1e6, 5e5, 0.0);
+        let clamped = clamp_luminance(firefly, 10.0);
+        assert!((luminance(clamped) - 10.0).abs() < 1e-4);
+        //same hue
+        assert!((clamped.x / clamped.y - 2.0).abs() < 1e-4);
+        assert_eq!(clamp_luminance(vec3(0.5, 0.5, 0.5), 10.0), vec3(0.5, 0.5, 0.5));
+
+        //a light far brighter than the limit, every sample that sees it gets scaled down
+        let (camera, _, _) = scene();
+        let mut objs = HittableList::new();
+        objs.add(Sphere::new(Vec3::ZERO, 1.0, DiffuseLight::with_intensity(SolidColor::new(vec4(1.0, 1.0, 1.0, 1.0)), 1e6)));
+        let config = RenderConfig {
+            max_sample_luminance: Some(2.0),
+            ..config(8, 8, 4)
+        };
+        let accum = render_accum(&camera, &objs, &HittableList::new(), &Background::sky(), &config);
+        assert!(accum.iter().all(|p| luminance(p.sum / p.samples as f32) <= 2.0 + 1e-4));
+    }
+
+    #[test]
+    fn stratified_offsets_tile_the_pixel() {
+        let mut rng = SmallRng::seed_from_u64(3);
+        let mut cells = [0; 16];
+        for i in 0..16 {
+            let (du, dv) = sample_offset(i, 16, true, &mut rng);
+            assert!((0.0..1.0).contains(&du) && (0.0..1.0).contains(&dv));
+            cells[(dv * 4.0) as usize * 4 + (du * 4.0) as usize] += 1;
+        }
+        assert_eq!(cells, [1; 16]);
+    }
+
+    //Average radiance of a sphere made of `material` filling the view, with nothing but the background around.
+    //Every path bounces once, so the result is the albedo of the material
+    fn furnace(material: impl Material + Send + Sync + 'static, background: &Background) -> Vec3 {
+        let camera = Camera::new(vec3(0.0, 0.0, 3.0), Vec3::ZERO, Vec3::Y, 20.0, 1.0, 0.0, 3.0);
+        let mut objs = HittableList::new();
+        objs.add(Sphere::new(Vec3::ZERO, 1.0, material));
+        let config = config(8, 8, 1024);
+        let accum = render_accum(&camera, &objs, &HittableList::new(), background, &config);
+        accum.iter().map(|p| p.sum / p.samples as f32).sum::<Vec3>() / accum.len() as f32
+    }
+
+    #[test]
+    fn pbr_white_furnace() {
+        let gray = vec4(0.5, 0.5, 0.5, 1.0);
+        let white = vec4(1.0, 1.0, 1.0, 1.0);
+        //sampled through the BSDF only, then through both the BSDF and the map with MIS
+        let solid = Background::Solid(white);
+        let map = Background::environment(RgbaImage::from_pixel(8, 4, Rgba([255, 255, 255, 255])));
+
+        assert!((furnace(Lambertian::color(gray), &solid) - Vec3::splat(0.5)).abs().max_element() < 1e-4);
+        //the dielectric coat reflects a little on top of the base, metals lose some energy with roughness
+        let dielectric = furnace(Pbr::color(gray, 0.0, 0.5), &solid);
+        assert!((dielectric - Vec3::splat(0.5)).abs().max_element() < 0.05, "Got {}", dielectric);
+        let metal = furnace(Pbr::color(gray, 1.0, 0.5), &solid);
+        assert!((metal - Vec3::splat(0.5)).abs().max_element() < 0.1, "Got {}", metal);
+
+        //light samples have to be weighted by the same BSDF
+        let sampled = furnace(Pbr::color(gray, 0.0, 0.5), &map);
+        assert!((sampled - dielectric).abs().max_element() < 0.01, "Got {} and {}", sampled, dielectric);
+        let sampled = furnace(Pbr::color(gray, 1.0, 0.5), &map);
+        assert!((sampled - metal).abs().max_element() < 0.01, "Got {} and {}", sampled, metal);
+    }
+
+    //The recursive tracer trace replaced, without light sampling, medium absorption or roulette
+    fn recursive_trace(ray: Ray, background: &Background, objs: &HittableList, config: &RenderConfig, depth: u32, rng: &mut SmallRng) -> Vec3 {
+        if depth == 0 {
+            return Vec3::ZERO;
+        }
+        let hr = match objs.hit(ray, t_min(&ray, config), f32::INFINITY, rng) {
+            Some(hr) => hr,
+            None => return linear(background.color(ray.direction)),
+        };
+        let emitted = linear(hr.material.emitted(&hr));
+        let record = match hr.material.scatter(&ray, &hr, rng) {
+            Some(record) => record,
+            None => return emitted,
+        };
+        let scattered = match &record.kind {
+            ScatterKind::Specular(scattered) => *scattered,
+            ScatterKind::Diffuse { pdf } => Ray::new(hr.position, pdf.generate(rng), ray.time),
+            ScatterKind::Lobe { .. } => unreachable!(),
+        };
+        emitted + linear(record.attenuation) * recursive_trace(scattered, background, objs, config, depth - 1, rng)
+    }
+
+    #[test]
+    fn matches_recursive_tracer() {
+        let (camera, mut objs, background) = scene();
+        objs.add(Sphere::new(vec3(1.5, 0.0, -1.0), 0.7, Metal::color(vec4(0.8, 0.6, 0.2, 1.0), 0.2)));
+        objs.add(Sphere::new(vec3(-1.5, 0.0, -1.0), 0.7, Dielectric::new(SolidColor::new(vec4(1.0, 1.0, 1.0, 1.0)), 1.5)));
+        objs.add(Sphere::new(vec3(0.0, -101.0, 0.0), 100.0, Lambertian::color(vec4(0.4, 0.5, 0.3, 1.0))));
+        let config = config(16, 16, 4);
+
+        for seed in 0..1000 {
+            let mut rng = SmallRng::seed_from_u64(seed);
+            let ray = camera.ray(rng.gen(), rng.gen(), &mut rng);
+            let iterative = trace(ray, &background, &objs, &HittableList::new(), &config, &mut rng.clone());
+            let recursive = recursive_trace(ray, &background, &objs, &config, config.max_depth, &mut rng);
+            assert!((iterative - recursive).abs().max_element() <= 1e-4 * recursive.max_element().max(1.0),
+                "Seed {}: {} != {}", seed, iterative, recursive);
+        }
+    }
+
+    #[test]
+    fn bvh_view_keeps_visit_stats() {
+        let (camera, _, background) = scene();
+        let mut spheres = HittableList::new();
+        for i in 0..8 {
+            spheres.add(Sphere::new(vec3(i as f32 * 0.3 - 1.0, 0.0, 0.0), 0.2, Lambertian::color(vec4(0.5, 0.5, 0.5, 1.0))));
+        }
+        let mut objs = HittableList::new();
+        objs.add(spheres.build_bvh());
+        let lights = HittableList::new();
+        let config = RenderConfig {
+            stats: true,
+            debug: Some(DebugView::BvhVisits { max: 10 }),
+            ..config(8, 8, 1)
+        };
+        let pass = RenderPass { camera: &camera, objs: &objs, lights: &lights, background: &background, config: &config, seed: 1, index: 0, samples: 1 };
+        let mut accum = vec![Pixel::default(); 64];
+        let stats = render_pass(&pass, &mut accum);
+        //one ray per pixel, and a tree over 8 spheres has 7 branches
+        assert!(stats.bvh_visits > 0 && stats.bvh_visits <= 64 * 7, "Got {} visits", stats.bvh_visits);
+    }
+
+    #[test]
+    fn epsilon_fits_huge_and_tiny_spheres() {
+        //a 0.005 radius sphere resting on a 20 radius one, like the mogu on the light
+        let (tiny, huge) = ((vec3(0.0, 0.005, 0.0), 0.005), (vec3(0.0, -20.0, 0.0), 20.0));
+        let mut objs = HittableList::new();
+        objs.add(Sphere::new(tiny.0, tiny.1, Lambertian::color(vec4(0.5, 0.5, 0.5, 1.0))));
+        objs.add(Sphere::new(huge.0, huge.1, Lambertian::color(vec4(0.5, 0.5, 0.5, 1.0))));
+        let config = RenderConfig::default();
+        let mut rng = SmallRng::seed_from_u64(88);
+
+        for (center, radius) in [tiny, huge] {
+            for _ in 0..1000 {
+                //from just above the top half of the sphere straight at its center
+                let mut outward = random_unit_vector(&mut rng);
+                outward.y = outward.y.abs();
+                let ray = Ray::new(center + outward * radius * 1.5, -outward, 0.0);
+                let hit = objs.hit(ray, t_min(&ray, &config), f32::INFINITY, &mut rng).expect("Sphere disappeared");
+                assert!(((hit.position - center).length() - radius).abs() < radius * 1e-3);
+
+                //leaving the surface it can't hit the same sphere again, it's convex
+                let direction = hit.normal + random_unit_vector(&mut rng);
+                let bounce = Ray::new(hit.position, direction, 0.0);
+                if let Some(again) = objs.hit(bounce, t_min(&bounce, &config), f32::INFINITY, &mut rng) {
+                    assert!(((again.position - center).length() - radius).abs() > radius * 1e-3,
+                        "Acne on the sphere of radius {} at {}", radius, hit.position);
+                }
+            }
+        }
+    }
+}
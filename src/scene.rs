@@ -0,0 +1,232 @@
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use bevy_math::{Vec3, vec4};
+use image::RgbaImage;
+use serde::Deserialize;
+use crate::aarect::{Quad, XYRect, XZRect, YZRect};
+use crate::background::Background;
+use crate::camera::Camera;
+use crate::material::{Dielectric, DiffuseLight, Lambertian, Metal, SharedMaterial};
+use crate::obj::{Cuboid, Hittable, HittableList, Sphere};
+use crate::texture::{Checker, ImageTexture, SharedTexture, SolidColor, UvChecker};
+use crate::types::Color;
+
+//Problems with the files a scene is built from
+#[derive(Debug)]
+pub enum SceneError {
+    Io { path: String, error: std::io::Error },
+    Parse { path: String, error: ron::error::SpannedError },
+    Asset { path: String, error: image::ImageError },
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::Io { path, error } => write!(f, "Failed to read {}: {}", path, error),
+            SceneError::Parse { path, error } => write!(f, "Invalid scene {}: {}", path, error),
+            SceneError::Asset { path, error } => write!(f, "Failed to load {}: {}", path, error),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+//Everything needed to render a scene file
+pub struct LoadedScene {
+    pub camera: Camera,
+    //None if the file doesn't pick one
+    pub background: Option<Background>,
+    pub objs: HittableList,
+    pub lights: HittableList,
+}
+
+//Layout of a scene file, see scenes/ for examples. Points and colors are (x, y, z) and (r, g, b) tuples
+#[derive(Deserialize)]
+struct SceneDesc {
+    camera: CameraDesc,
+    #[serde(default)]
+    background: Option<BackgroundDesc>,
+    objects: Vec<ObjectDesc>,
+}
+
+#[derive(Deserialize)]
+struct CameraDesc {
+    look_from: [f32; 3],
+    look_at: [f32; 3],
+    #[serde(default = "default_vup")]
+    vup: [f32; 3],
+    #[serde(default = "default_vfov")]
+    vfov: f32,
+    #[serde(default)]
+    aperture: f32,
+    //Defaults to the distance to look_at
+    #[serde(default)]
+    focus_distance: Option<f32>,
+}
+
+fn default_vup() -> [f32; 3] {
+    [0.0, 1.0, 0.0]
+}
+
+fn default_vfov() -> f32 {
+    20.0
+}
+
+#[derive(Deserialize)]
+enum BackgroundDesc {
+    Solid([f32; 3]),
+    Sky,
+    Gradient { top: [f32; 3], bottom: [f32; 3] },
+    Image(String),
+}
+
+#[derive(Deserialize)]
+enum ObjectDesc {
+    Sphere { center: [f32; 3], radius: f32, material: MaterialDesc },
+    XYRect { x0: f32, x1: f32, y0: f32, y1: f32, z: f32, material: MaterialDesc },
+    XZRect { x0: f32, x1: f32, z0: f32, z1: f32, y: f32, material: MaterialDesc },
+    YZRect { y0: f32, y1: f32, z0: f32, z1: f32, x: f32, material: MaterialDesc },
+    //Parallelogram with a corner at q and sides u and v
+    Quad { q: [f32; 3], u: [f32; 3], v: [f32; 3], material: MaterialDesc },
+    Cuboid { min: [f32; 3], max: [f32; 3], material: MaterialDesc },
+}
+
+#[derive(Deserialize)]
+enum MaterialDesc {
+    Lambertian(TextureDesc),
+    Metal { albedo: TextureDesc, fuzz: f32 },
+    Dielectric { ior: f32 },
+    DiffuseLight {
+        emit: TextureDesc,
+        #[serde(default = "default_intensity")]
+        intensity: f32,
+        //false to only emit from the side the normal points to
+        #[serde(default = "default_two_sided")]
+        two_sided: bool,
+    },
+}
+
+fn default_intensity() -> f32 {
+    1.0
+}
+
+fn default_two_sided() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+enum TextureDesc {
+    Solid([f32; 3]),
+    //Alternates in 3D space
+    Checker([f32; 3], [f32; 3]),
+    //Alternates over the surface, `scale` squares per unit of uv
+    UvChecker { even: [f32; 3], odd: [f32; 3], scale: f32 },
+    Image(String),
+}
+
+fn vec(v: [f32; 3]) -> Vec3 {
+    Vec3::from_array(v)
+}
+
+fn color(c: [f32; 3]) -> Color {
+    vec4(c[0], c[1], c[2], 1.0)
+}
+
+//Image paths in a scene file are relative to the file itself
+struct Loader<'a> {
+    directory: &'a Path,
+}
+
+impl Loader<'_> {
+    fn path(&self, path: &str) -> String {
+        self.directory.join(path).to_string_lossy().into_owned()
+    }
+
+    fn image(&self, path: &str) -> Result<RgbaImage, SceneError> {
+        let path = self.path(path);
+        match image::open(&path) {
+            Ok(image) => Ok(image.to_rgba8()),
+            Err(error) => Err(SceneError::Asset { path, error }),
+        }
+    }
+
+    fn texture(&self, desc: TextureDesc) -> Result<SharedTexture, SceneError> {
+        Ok(match desc {
+            TextureDesc::Solid(c) => Arc::new(SolidColor::new(color(c))),
+            TextureDesc::Checker(even, odd) => Arc::new(Checker::color(color(even), color(odd))),
+            TextureDesc::UvChecker { even, odd, scale } => Arc::new(UvChecker::color(color(even), color(odd), scale)),
+            TextureDesc::Image(path) => {
+                let path = self.path(&path);
+                Arc::new(ImageTexture::open(&path).map_err(|error| SceneError::Asset { path, error })?)
+            }
+        })
+    }
+
+    fn material(&self, desc: MaterialDesc) -> Result<SharedMaterial, SceneError> {
+        Ok(match desc {
+            MaterialDesc::Lambertian(albedo) => Arc::new(Lambertian::new(self.texture(albedo)?)),
+            MaterialDesc::Metal { albedo, fuzz } => Arc::new(Metal::new(self.texture(albedo)?, fuzz)),
+            MaterialDesc::Dielectric { ior } => Arc::new(Dielectric::new(SolidColor::new(vec4(1.0, 1.0, 1.0, 1.0)), ior)),
+            MaterialDesc::DiffuseLight { emit, intensity, two_sided } => {
+                Arc::new(DiffuseLight::with_intensity(self.texture(emit)?, intensity).with_two_sided(two_sided))
+            }
+        })
+    }
+
+    fn object(&self, desc: ObjectDesc) -> Result<(Arc<dyn Hittable + Send + Sync>, bool), SceneError> {
+        let material = match &desc {
+            ObjectDesc::Sphere { material, .. } | ObjectDesc::XYRect { material, .. } | ObjectDesc::XZRect { material, .. } |
+            ObjectDesc::YZRect { material, .. } | ObjectDesc::Quad { material, .. } | ObjectDesc::Cuboid { material, .. } => material,
+        };
+        let is_light = matches!(material, MaterialDesc::DiffuseLight { .. });
+        let obj: Arc<dyn Hittable + Send + Sync> = match desc {
+            ObjectDesc::Sphere { center, radius, material } => Arc::new(Sphere::new(vec(center), radius, self.material(material)?)),
+            ObjectDesc::XYRect { x0, x1, y0, y1, z, material } => Arc::new(XYRect::new(x0, x1, y0, y1, z, self.material(material)?)),
+            ObjectDesc::XZRect { x0, x1, z0, z1, y, material } => Arc::new(XZRect::new(x0, x1, z0, z1, y, self.material(material)?)),
+            ObjectDesc::YZRect { y0, y1, z0, z1, x, material } => Arc::new(YZRect::new(y0, y1, z0, z1, x, self.material(material)?)),
+            ObjectDesc::Quad { q, u, v, material } => Arc::new(Quad::new(vec(q), vec(u), vec(v), self.material(material)?)),
+            ObjectDesc::Cuboid { min, max, material } => Arc::new(Cuboid::new(vec(min), vec(max), self.material(material)?)),
+        };
+        Ok((obj, is_light))
+    }
+
+    fn background(&self, desc: BackgroundDesc) -> Result<Background, SceneError> {
+        Ok(match desc {
+            BackgroundDesc::Solid(c) => Background::Solid(color(c)),
+            BackgroundDesc::Sky => Background::sky(),
+            BackgroundDesc::Gradient { top, bottom } => Background::Gradient { top: color(top), bottom: color(bottom) },
+            BackgroundDesc::Image(path) => Background::environment(self.image(&path)?),
+        })
+    }
+}
+
+//Reads a RON scene file, the camera gets the aspect ratio of the output
+pub fn load_scene(path: &str, aspect_ratio: f32) -> Result<LoadedScene, SceneError> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|error| SceneError::Io { path: path.to_string(), error })?;
+    let desc: SceneDesc = ron::from_str(&text)
+        .map_err(|error| SceneError::Parse { path: path.to_string(), error })?;
+
+    let loader = Loader { directory: Path::new(path).parent().unwrap_or(Path::new("")) };
+
+    let cam = desc.camera;
+    let (look_from, look_at) = (vec(cam.look_from), vec(cam.look_at));
+    let focus_distance = cam.focus_distance.unwrap_or_else(|| (look_at - look_from).length());
+    let camera = Camera::new(look_from, look_at, vec(cam.vup), cam.vfov, aspect_ratio, cam.aperture, focus_distance);
+
+    let background = desc.background.map(|b| loader.background(b)).transpose()?;
+
+    let mut objs = HittableList::new();
+    let mut lights = HittableList::new();
+    for obj in desc.objects {
+        let (obj, is_light) = loader.object(obj)?;
+        //Lights are also sampled directly, so they need to be in both lists
+        if is_light {
+            lights.add_shared(obj.clone());
+        }
+        objs.add_shared(obj);
+    }
+
+    Ok(LoadedScene { camera, background, objs, lights })
+}
@@ -1,7 +1,19 @@
-use bevy_math::vec4;
+use std::sync::Arc;
+use bevy_math::{Vec3, vec4};
 use image::RgbaImage;
+use crate::obj::HitResult;
 use crate::perlin::Perlin;
-use crate::types::{Color, Point3};
+use crate::types::{Color, linear, Point3, scale};
+use crate::worley::Worley;
+
+//Noise from `seed`, or from thread_rng if None, which differs on every run whatever RenderConfig::seed is.
+//Reproducible renders need a seed, or a Perlin built from the scene's own RNG passed to from_perlin
+fn perlin(seed: Option<u64>) -> Perlin {
+    match seed {
+        Some(seed) => Perlin::with_seed(seed),
+        None => Perlin::new(&mut rand::thread_rng()),
+    }
+}
 
 pub trait Texture {
     //Hack to implement transparency for images
@@ -9,10 +21,43 @@ pub trait Texture {
         true
     }
 
-    fn value(&self, u: f32, v: f32, point: Point3) -> Color;
+    //Partial coverage of points that pass hack_solid
+    fn alpha(&self, _: f32, _: f32, _: Point3) -> f32 {
+        1.0
+    }
+
+    //`normal` is the surface normal at `point`, for textures that depend on the orientation
+    fn value(&self, u: f32, v: f32, point: Point3, normal: Vec3) -> Color;
+
+    //What materials look up, textures that can work in object space override it to use hit.local
+    fn value_at(&self, hit: &HitResult) -> Color {
+        self.value(hit.u, hit.v, hit.position, hit.normal)
+    }
+}
+
+//For textures only known at runtime, e.g. from a scene file
+pub type SharedTexture = Arc<dyn Texture + Send + Sync>;
+
+impl<T: Texture + ?Sized> Texture for Arc<T> {
+    fn hack_solid(&self, u: f32, v: f32, point: Point3) -> bool {
+        (**self).hack_solid(u, v, point)
+    }
+
+    fn alpha(&self, u: f32, v: f32, point: Point3) -> f32 {
+        (**self).alpha(u, v, point)
+    }
+
+    fn value(&self, u: f32, v: f32, point: Point3, normal: Vec3) -> Color {
+        (**self).value(u, v, point, normal)
+    }
+
+    fn value_at(&self, hit: &HitResult) -> Color {
+        (**self).value_at(hit)
+    }
 }
 
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub struct SolidColor {
     color: Color,
 }
@@ -26,46 +71,129 @@ impl SolidColor {
 }
 
 impl Texture for SolidColor {
-    fn value(&self, _: f32, _: f32, _: Point3) -> Color {
+    fn value(&self, _: f32, _: f32, _: Point3, _: Vec3) -> Color {
         self.color
     }
 }
 
+//Which position a Checker alternates over, both give a 3D pattern that objects are carved out of.
+//For a 2D pattern laid out in texture coordinates use UvChecker
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub enum CheckerDomain {
+    //Position of the hit in the scene, the pattern stays put when objects move
+    World,
+    //Position before Translate, Rotate or Transform, the pattern moves with the object.
+    //Only works for the texture of a material, nested in other textures it's the same as World
+    Object,
+}
+
+//Squares are pi / scale wide, a scale of 0 gives just the even texture
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub struct Checker<E: Texture, O: Texture> {
     even: E,
     odd: O,
     mul: f32,
+    domain: CheckerDomain,
 }
 
 impl<E: Texture, O: Texture> Checker<E, O> {
     pub fn new(even: E, odd: O) -> Self {
+        Self::with_scale(even, odd, 10.0)
+    }
+
+    pub fn with_scale(even: E, odd: O, scale: f32) -> Self {
         Self {
             even,
             odd,
-            mul: 10.0,
+            mul: scale,
+            domain: CheckerDomain::World,
         }
     }
+
+    pub fn with_domain(mut self, domain: CheckerDomain) -> Self {
+        self.domain = domain;
+        self
+    }
 }
 
 impl Checker<SolidColor, SolidColor> {
     pub fn color(even: Color, odd: Color) -> Self {
         Self::new(SolidColor::new(even), SolidColor::new(odd))
     }
+
+    pub fn color_with_scale(even: Color, odd: Color, scale: f32) -> Self {
+        Self::with_scale(SolidColor::new(even), SolidColor::new(odd), scale)
+    }
+}
+
+impl<E: Texture, O: Texture> Checker<E, O> {
+    fn is_odd(&self, point: Point3) -> bool {
+        (self.mul * point.x).sin() * (self.mul * point.y).sin() * (self.mul * point.z).sin() < 0.0
+    }
 }
 
 impl<E: Texture, O: Texture> Texture for Checker<E, O> {
-    fn value(&self, u: f32, v: f32, point: Point3) -> Color {
-        let sines = (self.mul * point.x).sin() * (self.mul * point.y).sin() * (self.mul * point.z).sin();
-        if sines < 0.0 {
-            self.odd.value(u, v, point)
+    fn value(&self, u: f32, v: f32, point: Point3, normal: Vec3) -> Color {
+        if self.is_odd(point) {
+            self.odd.value(u, v, point, normal)
+        } else {
+            self.even.value(u, v, point, normal)
+        }
+    }
+
+    fn value_at(&self, hit: &HitResult) -> Color {
+        let point = match self.domain {
+            CheckerDomain::World => hit.position,
+            CheckerDomain::Object => hit.local,
+        };
+        if self.is_odd(point) {
+            self.odd.value_at(hit)
+        } else {
+            self.even.value_at(hit)
+        }
+    }
+}
+
+//Checkerboard laid out in texture space, so it follows the surface instead of world position
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct UvChecker<E: Texture, O: Texture> {
+    even: E,
+    odd: O,
+    scale: f32,
+}
+
+impl<E: Texture, O: Texture> UvChecker<E, O> {
+    pub fn new(even: E, odd: O, scale: f32) -> Self {
+        Self {
+            even,
+            odd,
+            scale,
+        }
+    }
+}
+
+impl UvChecker<SolidColor, SolidColor> {
+    pub fn color(even: Color, odd: Color, scale: f32) -> Self {
+        Self::new(SolidColor::new(even), SolidColor::new(odd), scale)
+    }
+}
+
+impl<E: Texture, O: Texture> Texture for UvChecker<E, O> {
+    fn value(&self, u: f32, v: f32, point: Point3, normal: Vec3) -> Color {
+        let parity = (u * self.scale).floor() as i32 + (v * self.scale).floor() as i32;
+        if parity.rem_euclid(2) == 0 {
+            self.even.value(u, v, point, normal)
         } else {
-            self.even.value(u, v, point)
+            self.odd.value(u, v, point, normal)
         }
     }
 }
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub struct Noise<T> {
     texture: T,
     scale: f32,
@@ -74,64 +202,222 @@ pub struct Noise<T> {
 
 impl<T> Noise<T> {
     pub fn new(texture: T, scale: f32) -> Self {
+        Self::seeded(texture, scale, None)
+    }
+
+    pub fn seeded(texture: T, scale: f32, seed: Option<u64>) -> Self {
+        Self::from_perlin(texture, scale, perlin(seed))
+    }
+
+    pub fn from_perlin(texture: T, scale: f32, noise: Perlin) -> Self {
         Self {
             texture,
             scale,
-            noise: Perlin::new(),
+            noise,
         }
     }
 }
 
 impl<T: Texture> Texture for Noise<T> {
-    fn value(&self, u: f32, v: f32, point: Point3) -> Color {
-        self.texture.value(u, v, point) * self.noise.normalized_noise(point * self.scale)
+    fn value(&self, u: f32, v: f32, point: Point3, normal: Vec3) -> Color {
+        scale(self.texture.value(u, v, point, normal), self.noise.normalized_noise(point * self.scale))
     }
 }
 
+//Octaves of noise summed by Turbulence and TurbulencePhase, fewer is faster but less detailed
+pub const DEFAULT_TURBULENCE_DEPTH: u32 = 7;
+
 #[derive(Clone)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub struct Turbulence<T> {
     texture: T,
     scale: f32,
+    depth: u32,
     noise: Perlin,
 }
 
 impl<T> Turbulence<T> {
     pub fn new(texture: T, scale: f32) -> Self {
+        Self::with_depth(texture, scale, DEFAULT_TURBULENCE_DEPTH)
+    }
+
+    pub fn with_depth(texture: T, scale: f32, depth: u32) -> Self {
+        Self::seeded(texture, scale, depth, None)
+    }
+
+    pub fn seeded(texture: T, scale: f32, depth: u32, seed: Option<u64>) -> Self {
+        let mut res = Self::from_perlin(texture, scale, perlin(seed));
+        res.depth = depth;
+        res
+    }
+
+    pub fn from_perlin(texture: T, scale: f32, noise: Perlin) -> Self {
         Self {
             texture,
             scale,
-            noise: Perlin::new(),
+            depth: DEFAULT_TURBULENCE_DEPTH,
+            noise,
         }
     }
 }
 
 impl<T: Texture> Texture for Turbulence<T> {
-    fn value(&self, u: f32, v: f32, point: Point3) -> Color {
-        self.texture.value(u, v, point) * self.noise.turbulence(point * self.scale, 7)
+    fn value(&self, u: f32, v: f32, point: Point3, normal: Vec3) -> Color {
+        scale(self.texture.value(u, v, point, normal), self.noise.turbulence(point * self.scale, self.depth))
     }
 }
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub struct TurbulencePhase<T> {
     texture: T,
     scale: f32,
+    depth: u32,
     noise: Perlin,
 }
 
 impl<T> TurbulencePhase<T> {
     pub fn new(texture: T, scale: f32) -> Self {
+        Self::with_depth(texture, scale, DEFAULT_TURBULENCE_DEPTH)
+    }
+
+    pub fn with_depth(texture: T, scale: f32, depth: u32) -> Self {
+        Self::seeded(texture, scale, depth, None)
+    }
+
+    pub fn seeded(texture: T, scale: f32, depth: u32, seed: Option<u64>) -> Self {
+        let mut res = Self::from_perlin(texture, scale, perlin(seed));
+        res.depth = depth;
+        res
+    }
+
+    pub fn from_perlin(texture: T, scale: f32, noise: Perlin) -> Self {
         Self {
             texture,
             scale,
-            noise: Perlin::new(),
+            depth: DEFAULT_TURBULENCE_DEPTH,
+            noise,
         }
     }
 }
 
 impl<T: Texture> Texture for TurbulencePhase<T> {
-    fn value(&self, u: f32, v: f32, point: Point3) -> Color {
-        let mul = (self.scale * point.z + 10.0 * self.noise.turbulence(point, 7)).sin() * 0.5 + 0.5;
-        self.texture.value(u, v, point) * mul
+    fn value(&self, u: f32, v: f32, point: Point3, normal: Vec3) -> Color {
+        let mul = (self.scale * point.z + 10.0 * self.noise.turbulence(point, self.depth)).sin() * 0.5 + 0.5;
+        scale(self.texture.value(u, v, point, normal), mul)
+    }
+}
+
+//Veined stone look, the turbulence shifts the phase of a sine wave along z.
+//Just TurbulencePhase with the depth up front
+pub struct Marble;
+
+impl Marble {
+    pub fn with_texture<T>(texture: T, scale: f32, depth: u32) -> TurbulencePhase<T> {
+        TurbulencePhase::with_depth(texture, scale, depth)
+    }
+
+    pub fn seeded<T>(texture: T, scale: f32, depth: u32, seed: Option<u64>) -> TurbulencePhase<T> {
+        TurbulencePhase::seeded(texture, scale, depth, seed)
+    }
+
+    pub fn from_perlin<T>(texture: T, scale: f32, depth: u32, noise: Perlin) -> TurbulencePhase<T> {
+        let mut res = TurbulencePhase::from_perlin(texture, scale, noise);
+        res.depth = depth;
+        res
+    }
+
+    pub fn color(color: Color, scale: f32, depth: u32) -> TurbulencePhase<SolidColor> {
+        Self::with_texture(SolidColor::new(color), scale, depth)
+    }
+}
+
+//Soft cloud-like noise from fBm, with the usual doubling frequency and halving amplitude
+#[derive(Clone)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct Cloud<T> {
+    texture: T,
+    scale: f32,
+    octaves: u32,
+    noise: Perlin,
+}
+
+impl<T> Cloud<T> {
+    pub fn new(texture: T, scale: f32, octaves: u32) -> Self {
+        Self::seeded(texture, scale, octaves, None)
+    }
+
+    pub fn seeded(texture: T, scale: f32, octaves: u32, seed: Option<u64>) -> Self {
+        Self::from_perlin(texture, scale, octaves, perlin(seed))
+    }
+
+    pub fn from_perlin(texture: T, scale: f32, octaves: u32, noise: Perlin) -> Self {
+        Self {
+            texture,
+            scale,
+            octaves,
+            noise,
+        }
+    }
+}
+
+impl<T: Texture> Texture for Cloud<T> {
+    fn value(&self, u: f32, v: f32, point: Point3, normal: Vec3) -> Color {
+        let fbm = self.noise.fbm(point * self.scale, self.octaves, 2.0, 0.5);
+        scale(self.texture.value(u, v, point, normal), (fbm * 0.5 + 0.5).clamp(0.0, 1.0))
+    }
+}
+
+//Which distances of the Worley noise a CellularTexture shows
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub enum CellularMode {
+    //Distance to the nearest feature point, dark spots in the middle of bright cells
+    F1,
+    //Gap between the two nearest feature points, dark only along the borders between cells like cracks
+    F2MinusF1,
+}
+
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct CellularTexture<T> {
+    texture: T,
+    scale: f32,
+    mode: CellularMode,
+    noise: Worley,
+}
+
+impl<T> CellularTexture<T> {
+    pub fn new(texture: T, scale: f32, mode: CellularMode) -> Self {
+        Self::seeded(texture, scale, mode, None)
+    }
+
+    pub fn seeded(texture: T, scale: f32, mode: CellularMode, seed: Option<u64>) -> Self {
+        let noise = match seed {
+            Some(seed) => Worley::with_seed(seed),
+            None => Worley::new(&mut rand::thread_rng()),
+        };
+        Self::from_worley(texture, scale, mode, noise)
+    }
+
+    pub fn from_worley(texture: T, scale: f32, mode: CellularMode, noise: Worley) -> Self {
+        Self {
+            texture,
+            scale,
+            mode,
+            noise,
+        }
+    }
+}
+
+impl<T: Texture> Texture for CellularTexture<T> {
+    fn value(&self, u: f32, v: f32, point: Point3, normal: Vec3) -> Color {
+        let (f1, f2) = self.noise.distances(point * self.scale);
+        let mul = match self.mode {
+            CellularMode::F1 => f1,
+            CellularMode::F2MinusF1 => f2 - f1,
+        };
+        scale(self.texture.value(u, v, point, normal), mul.clamp(0.0, 1.0))
     }
 }
 
@@ -153,31 +439,172 @@ impl<A: Texture, B: Texture, C: Texture> MultiplyAdd<A, B, C> {
 }
 
 impl<A: Texture, B: Texture, C: Texture> Texture for MultiplyAdd<A, B, C> {
-    fn value(&self, u: f32, v: f32, point: Point3) -> Color {
-        let a = self.a.value(u, v, point);
-        let b = self.b.value(u, v, point);
-        let c = self.c.value(u, v, point);
-        (a + b * c).clamp(Color::splat(0.0), Color::splat(1.0))
+    fn value(&self, u: f32, v: f32, point: Point3, normal: Vec3) -> Color {
+        let a = linear(self.a.value(u, v, point, normal));
+        let b = linear(self.b.value(u, v, point, normal));
+        let c = linear(self.c.value(u, v, point, normal));
+        (a + b * c).clamp(Vec3::ZERO, Vec3::ONE).extend(1.0)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum GradientAxis {
+    U,
+    V,
+    //Height of the hit point, from y0 to y1
+    Y { y0: f32, y1: f32 },
+}
+
+//Linear interpolation from `start` to `end` along an axis
+#[derive(Copy, Clone)]
+pub struct Gradient {
+    start: Color,
+    end: Color,
+    axis: GradientAxis,
+}
+
+impl Gradient {
+    pub fn new(start: Color, end: Color, axis: GradientAxis) -> Self {
+        Self {
+            start,
+            end,
+            axis,
+        }
+    }
+
+    pub fn vertical(top: Color, bottom: Color) -> Self {
+        Self::new(bottom, top, GradientAxis::V)
+    }
+
+    pub fn horizontal(left: Color, right: Color) -> Self {
+        Self::new(left, right, GradientAxis::U)
+    }
+
+    pub fn height(bottom: Color, top: Color, y0: f32, y1: f32) -> Self {
+        Self::new(bottom, top, GradientAxis::Y { y0, y1 })
+    }
+}
+
+impl Texture for Gradient {
+    fn value(&self, u: f32, v: f32, point: Point3, _: Vec3) -> Color {
+        let t = match self.axis {
+            GradientAxis::U => u,
+            GradientAxis::V => v,
+            GradientAxis::Y { y0, y1 } => (point.y - y0) / (y1 - y0),
+        };
+        self.start.lerp(self.end, t.clamp(0.0, 1.0))
+    }
+}
+
+//Projects the texture along the x, y and z axes and blends the three by how much the surface
+//faces each of them, for surfaces without usable texture coordinates
+#[derive(Clone)]
+pub struct Triplanar<T> {
+    texture: T,
+    scale: f32,
+}
+
+impl<T> Triplanar<T> {
+    pub fn new(texture: T, scale: f32) -> Self {
+        Self {
+            texture,
+            scale,
+        }
+    }
+}
+
+impl<T: Texture> Texture for Triplanar<T> {
+    fn value(&self, _: f32, _: f32, point: Point3, normal: Vec3) -> Color {
+        let weights = normal * normal;
+        let weights = weights / (weights.x + weights.y + weights.z);
+        let p = point * self.scale;
+        let x = self.texture.value(p.y, p.z, point, normal);
+        let y = self.texture.value(p.x, p.z, point, normal);
+        let z = self.texture.value(p.x, p.y, point, normal);
+        x * weights.x + y * weights.y + z * weights.z
     }
 }
 
+//How texture coordinates outside of [0, 1] are mapped back into the image
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub enum Wrap {
+    Clamp,
+    Repeat,
+    //Repeats, flipping every other copy
+    Mirror,
+}
+
+impl Wrap {
+    pub fn apply(self, x: f32) -> f32 {
+        match self {
+            Wrap::Clamp => x.clamp(0.0, 1.0),
+            Wrap::Repeat => x.rem_euclid(1.0),
+            Wrap::Mirror => {
+                let x = x.rem_euclid(2.0);
+                if x > 1.0 {
+                    2.0 - x
+                } else {
+                    x
+                }
+            }
+        }
+    }
+}
+
+//How the alpha channel of an image is used
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub enum AlphaMode {
+    //Mostly transparent pixels are cut out, the rest are fully solid
+    Cutoff,
+    //Rays go through with probability 1 - alpha, which antialiases the edges
+    Blend,
+}
+
+//Cloning only bumps the refcount, the pixels are shared
 #[derive(Clone)]
 pub struct ImageTexture {
-    image: RgbaImage,
+    image: Arc<RgbaImage>,
+    //Where the image came from, textures are serialized as this path
+    path: Option<Arc<str>>,
+    wrap: Wrap,
+    alpha_mode: AlphaMode,
 }
 
 impl ImageTexture {
     pub fn new(image: RgbaImage) -> Self {
+        Self::shared(Arc::new(image))
+    }
+
+    pub fn shared(image: Arc<RgbaImage>) -> Self {
         Self {
             image,
+            path: None,
+            wrap: Wrap::Clamp,
+            alpha_mode: AlphaMode::Cutoff,
         }
     }
-}
 
-impl Texture for ImageTexture {
-    fn hack_solid(&self, u: f32, v: f32, _: Point3) -> bool {
-        let u = 1.0 - u.clamp(0.0, 1.0);
-        let v = 1.0 - v.clamp(0.0, 1.0);
+    pub fn open(path: &str) -> Result<Self, image::ImageError> {
+        let mut texture = Self::new(image::open(path)?.to_rgba8());
+        texture.path = Some(path.into());
+        Ok(texture)
+    }
+
+    pub fn with_wrap(mut self, wrap: Wrap) -> Self {
+        self.wrap = wrap;
+        self
+    }
+
+    pub fn with_alpha_mode(mut self, alpha_mode: AlphaMode) -> Self {
+        self.alpha_mode = alpha_mode;
+        self
+    }
+
+    fn pixel(&self, u: f32, v: f32) -> [u8; 4] {
+        let u = 1.0 - self.wrap.apply(u);
+        let v = 1.0 - self.wrap.apply(v);
 
         let mut i = (self.image.width() as f32 * u) as u32;
         let mut j = (self.image.height() as f32 * v) as u32;
@@ -188,26 +615,135 @@ impl Texture for ImageTexture {
             j = self.image.height() - 1;
         }
 
-        let pixel = self.image.get_pixel(i, j).0;
-        pixel[3] > 10
+        self.image.get_pixel(i, j).0
     }
+}
 
-    fn value(&self, u: f32, v: f32, _point: Point3) -> Color {
-        let u = 1.0 - u.clamp(0.0, 1.0);
-        let v = 1.0 - v.clamp(0.0, 1.0);
+#[cfg(feature = "serialize")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ImageTextureFile {
+    path: String,
+    wrap: Wrap,
+    alpha_mode: AlphaMode,
+}
 
-        let mut i = (self.image.width() as f32 * u) as u32;
-        let mut j = (self.image.height() as f32 * v) as u32;
-        if i >= self.image.width() {
-            i = self.image.width() - 1;
+//Only the path is written, the image is loaded again when deserializing
+#[cfg(feature = "serialize")]
+impl serde::Serialize for ImageTexture {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let path = match &self.path {
+            Some(path) => path.to_string(),
+            None => return Err(serde::ser::Error::custom("image texture wasn't loaded from a file")),
+        };
+        serde::Serialize::serialize(&ImageTextureFile { path, wrap: self.wrap, alpha_mode: self.alpha_mode }, serializer)
+    }
+}
+
+#[cfg(feature = "serialize")]
+impl<'de> serde::Deserialize<'de> for ImageTexture {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let file: ImageTextureFile = serde::Deserialize::deserialize(deserializer)?;
+        let texture = ImageTexture::open(&file.path).map_err(serde::de::Error::custom)?;
+        Ok(texture.with_wrap(file.wrap).with_alpha_mode(file.alpha_mode))
+    }
+}
+
+impl Texture for ImageTexture {
+    fn hack_solid(&self, u: f32, v: f32, _: Point3) -> bool {
+        let pixel = self.pixel(u, v);
+        match self.alpha_mode {
+            AlphaMode::Cutoff => pixel[3] > 10,
+            AlphaMode::Blend => pixel[3] > 0,
         }
-        if j >= self.image.height() {
-            j = self.image.height() - 1;
+    }
+
+    fn alpha(&self, u: f32, v: f32, _: Point3) -> f32 {
+        match self.alpha_mode {
+            AlphaMode::Cutoff => 1.0,
+            AlphaMode::Blend => self.pixel(u, v)[3] as f32 / 255.0,
         }
+    }
 
+    fn value(&self, u: f32, v: f32, _: Point3, _: Vec3) -> Color {
         let scale = 1.0 / 255.0;
-        let pixel = self.image.get_pixel(i, j).0;
-        let (r, g, b, a) = (pixel[0] as f32 * scale, pixel[1] as f32 * scale, pixel[2] as f32 * scale, pixel[3] as f32 * scale);
-        vec4(r, g, b, a)
+        let pixel = self.pixel(u, v);
+        let (r, g, b) = (pixel[0] as f32 * scale, pixel[1] as f32 * scale, pixel[2] as f32 * scale);
+        //alpha only matters for hack_solid and alpha
+        vec4(r, g, b, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_math::vec3;
+    use rand::SeedableRng;
+    use rand::rngs::SmallRng;
+    use crate::material::Lambertian;
+    use crate::obj::{Hittable, Sphere, Translate};
+    use crate::types::Ray;
+    use super::*;
+
+    #[test]
+    fn image_textures_share_pixels() {
+        let image = Arc::new(RgbaImage::new(4, 4));
+        let texture = ImageTexture::shared(image.clone());
+        let clones: Vec<ImageTexture> = (0..10).map(|_| texture.clone()).collect();
+        //one reference here, one in the texture and one per clone, no copies of the pixels
+        assert_eq!(Arc::strong_count(&image), 12);
+        assert!(clones.iter().all(|c| Arc::ptr_eq(&c.image, &image)));
+        drop(clones);
+        assert_eq!(Arc::strong_count(&image), 2);
+    }
+
+    #[test]
+    fn zero_scale_checker_is_solid() {
+        let (even, odd) = (vec4(1.0, 1.0, 1.0, 1.0), vec4(0.0, 0.0, 0.0, 1.0));
+        let checker = Checker::color_with_scale(even, odd, 0.0);
+        for i in 0..1000 {
+            //negative coordinates too, where the sines of a scaled checker flip sign
+            let x = i as f32 * 0.37 - 150.0;
+            let p = vec3(x, -x * 0.5, x * 0.25);
+            assert_eq!(checker.value(x, -x, p, Vec3::Y), even, "Parity flipped at {}", p);
+        }
+        //while any other scale alternates
+        let checker = Checker::color_with_scale(even, odd, 1.0);
+        assert_eq!(checker.value(0.0, 0.0, vec3(1.0, 1.0, 1.0), Vec3::Y), even);
+        assert_eq!(checker.value(0.0, 0.0, vec3(-1.0, 1.0, 1.0), Vec3::Y), odd);
+    }
+
+    #[test]
+    fn object_space_checker_moves_with_the_object() {
+        let (even, odd) = (vec4(1.0, 1.0, 1.0, 1.0), vec4(0.0, 0.0, 0.0, 1.0));
+        let world = Checker::color_with_scale(even, odd, 1.0);
+        let object = world.with_domain(CheckerDomain::Object);
+        let mut rng = SmallRng::seed_from_u64(1);
+        let mut world_colors = Vec::new();
+        for i in 0..20 {
+            //the same point of the sphere every time, while the sphere slides along x
+            let offset = vec3(i as f32 * 0.7, 0.0, 0.0);
+            let sphere = Translate::new(Sphere::new(Vec3::ZERO, 1.0, Lambertian::color(even)), offset);
+            let ray = Ray::new(offset + vec3(0.3, 0.4, 5.0), -Vec3::Z, 0.0);
+            let hit = sphere.hit(&ray, 0.001, f32::MAX, &mut rng).unwrap();
+            assert!((hit.local - (hit.position - offset)).length() < 1e-5);
+            assert_eq!(object.value_at(&hit), even, "Pattern moved at offset {}", offset);
+            world_colors.push(world.value_at(&hit));
+        }
+        assert!(world_colors.contains(&even) && world_colors.contains(&odd));
+    }
+    #[test]
+    fn white_textures_have_unit_weight() {
+        let white = vec4(1.0, 1.0, 1.0, 1.0);
+        let image = ImageTexture::new(RgbaImage::from_pixel(2, 2, image::Rgba([255, 255, 255, 255])));
+        let textures: [(&str, Box<dyn Texture>); 4] = [
+            ("solid", Box::new(SolidColor::new(white))),
+            ("image", Box::new(image)),
+            ("checker", Box::new(Checker::color(white, white))),
+            ("multiply add", Box::new(MultiplyAdd::new(SolidColor::new(white), SolidColor::new(white), SolidColor::new(white)))),
+        ];
+        for (name, texture) in &textures {
+            let color = texture.value(0.25, 0.75, vec3(0.3, -1.2, 2.0), Vec3::Y);
+            assert_eq!(color.w, 1.0, "{} has weight {}", name, color.w);
+            assert_eq!(linear(color), Vec3::ONE, "{} is {}", name, color);
+        }
     }
 }
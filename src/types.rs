@@ -1,20 +1,38 @@
-use bevy_math::{Vec3, Vec4};
+use bevy_math::{Vec3, vec3, Vec4, Vec4Swizzles};
 
 pub type Point3 = Vec3;
 
+//RGB plus a weight in w that the RGB gets multiplied by (see `linear`), opaque colors use w = 1.
+//Transparency is not stored here, textures report it through `hack_solid` instead
 pub type Color = Vec4;
 
+pub fn linear(c: Color) -> Vec3 {
+    c.xyz() * c.w
+}
+
+//Scales the brightness of a color without touching its weight
+pub fn scale(c: Color, s: f32) -> Color {
+    (c.xyz() * s).extend(c.w)
+}
+
+//Perceived brightness of a linear color, with the Rec. 709 weights
+pub fn luminance(color: Vec3) -> f32 {
+    color.dot(vec3(0.2126, 0.7152, 0.0722))
+}
+
 #[derive(Clone, Copy, PartialEq)]
 pub struct Ray {
     pub origin: Point3,
     pub direction: Vec3,
+    pub time: f32,
 }
 
 impl Ray {
-    pub fn new(origin: Point3, direction: Vec3) -> Self {
+    pub fn new(origin: Point3, direction: Vec3, time: f32) -> Self {
         Self {
             origin,
             direction,
+            time,
         }
     }
 
@@ -8,6 +8,19 @@ pub fn reflectance(cos: f32, ref_idx: f32) -> f32 {
     r0 + (1.0 - r0) * (1.0 - cos).powf(5.0)
 }
 
+//Exact Fresnel equations for unpolarized light, 1 on total internal reflection
+#[inline(always)]
+pub fn fresnel(cos: f32, etai_over_etat: f32) -> f32 {
+    let sin_t2 = etai_over_etat * etai_over_etat * (1.0 - cos * cos);
+    if sin_t2 >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin_t2).sqrt();
+    let rs = (etai_over_etat * cos - cos_t) / (etai_over_etat * cos + cos_t);
+    let rp = (cos - etai_over_etat * cos_t) / (cos + etai_over_etat * cos_t);
+    0.5 * (rs * rs + rp * rp)
+}
+
 #[inline(always)]
 pub fn refract(uv: Vec3, normal: Vec3, etai_over_etat: f32) -> Vec3 {
     let cos_theta = (-uv).dot(normal).min(1.0);
@@ -27,22 +40,28 @@ pub fn near_zero(v: Vec3) -> bool {
 }
 
 #[inline(always)]
+//The zero vector has no direction, it's returned as is instead of turning into NaNs
 pub fn unit_vector(v: Vec3) -> Vec3 {
-    v / v.length()
+    let length = v.length();
+    if length > 0.0 && length.is_finite() {
+        v / length
+    } else {
+        Vec3::ZERO
+    }
 }
 
 #[inline(always)]
-pub fn random_vector(min: f32, max: f32) -> Vec3 {
-    let mut rng = rand::thread_rng();
+pub fn random_vector(min: f32, max: f32, rng: &mut impl Rng) -> Vec3 {
     vec3(rng.gen_range(min..=max), rng.gen_range(min..=max), rng.gen_range(min..=max))
 }
 
 #[inline(always)]
-pub fn random_in_unit_sphere() -> Vec3 {
-    let mut rng = rand::thread_rng();
+pub fn random_in_unit_sphere(rng: &mut impl Rng) -> Vec3 {
     loop {
         let v = vec3(rng.gen_range(-1.0..=1.0), rng.gen_range(-1.0..=1.0), rng.gen_range(-1.0..=1.0));
-        if v.length_squared() >= 1.0 {
+        //a zero vector has no direction, random_unit_vector would turn it into NaNs
+        let l = v.length_squared();
+        if l >= 1.0 || l == 0.0 {
             continue;
         }
         return v;
@@ -50,28 +69,144 @@ pub fn random_in_unit_sphere() -> Vec3 {
 }
 
 #[inline(always)]
-pub fn random_in_unit_disk() -> Vec3 {
-    let mut rng = rand::thread_rng();
+pub fn random_in_unit_disk(rng: &mut impl Rng) -> Vec3 {
     loop {
         let v = vec3(rng.gen_range(-1.0..=1.0), rng.gen_range(-1.0..=1.0), 0.0);
-        if v.length_squared() >= 1.0 {
+        let l = v.length_squared();
+        if l >= 1.0 || l == 0.0 {
             continue;
         }
         return v;
     }
 }
 
+//Maps the unit square onto the unit disk keeping areas proportional (Shirley and Chiu's concentric mapping),
+//so evenly spread points stay evenly spread, unlike with rejection sampling
+#[inline(always)]
+pub fn concentric_disk(u: f32, v: f32) -> Vec3 {
+    let a = 2.0 * u - 1.0;
+    let b = 2.0 * v - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return Vec3::ZERO;
+    }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, std::f32::consts::FRAC_PI_4 * (b / a))
+    } else {
+        (b, std::f32::consts::FRAC_PI_2 - std::f32::consts::FRAC_PI_4 * (a / b))
+    };
+    vec3(r * theta.cos(), r * theta.sin(), 0.0)
+}
+
+//Same distribution as random_in_unit_disk, always using exactly two random numbers
 #[inline(always)]
-pub fn random_unit_vector() -> Vec3 {
-    unit_vector(random_in_unit_sphere())
+pub fn random_in_unit_disk_concentric(rng: &mut impl Rng) -> Vec3 {
+    concentric_disk(rng.gen(), rng.gen())
 }
 
 #[inline(always)]
-pub fn random_in_hemisphere(normal: Vec3) -> Vec3 {
-    let in_unit_sphere = random_in_unit_sphere();
+pub fn random_unit_vector(rng: &mut impl Rng) -> Vec3 {
+    unit_vector(random_in_unit_sphere(rng))
+}
+
+#[inline(always)]
+pub fn random_in_hemisphere(normal: Vec3, rng: &mut impl Rng) -> Vec3 {
+    let in_unit_sphere = random_in_unit_sphere(rng);
     if in_unit_sphere.dot(normal) > 0.0 {
         in_unit_sphere
     } else {
         -in_unit_sphere
     }
-}
\ No newline at end of file
+}
+
+//Point at `radius` from the origin, `phi` radians away from the y axis and `theta` radians around it starting at x
+#[inline(always)]
+pub fn polar_to_xyz(radius: f32, phi: f32, theta: f32) -> Vec3 {
+    vec3(
+        radius * phi.sin() * theta.cos(),
+        radius * phi.cos(),
+        radius * phi.sin() * theta.sin(),
+    )
+}
+
+//Random direction in the z+ hemisphere, with density proportional to the cosine to z
+#[inline(always)]
+pub fn random_cosine_direction(rng: &mut impl Rng) -> Vec3 {
+    let r1: f32 = rng.gen();
+    let r2: f32 = rng.gen();
+
+    let phi = 2.0 * std::f32::consts::PI * r1;
+    let x = phi.cos() * r2.sqrt();
+    let y = phi.sin() * r2.sqrt();
+    let z = (1.0 - r2).sqrt();
+
+    vec3(x, y, z)
+}
+
+//Orthonormal basis with w pointing along a given vector
+pub struct Onb {
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3,
+}
+
+impl Onb {
+    pub fn from_w(n: Vec3) -> Self {
+        let w = unit_vector(n);
+        let a = if w.x.abs() > 0.9 {
+            vec3(0.0, 1.0, 0.0)
+        } else {
+            vec3(1.0, 0.0, 0.0)
+        };
+        let v = unit_vector(w.cross(a));
+        let u = w.cross(v);
+        Self {
+            u,
+            v,
+            w,
+        }
+    }
+
+    pub fn local(&self, a: Vec3) -> Vec3 {
+        a.x * self.u + a.y * self.v + a.z * self.w
+    }
+}
+
+//Random direction towards a sphere of the given radius, distance_squared away, in local coordinates (z towards the center)
+#[inline(always)]
+pub fn random_to_sphere(radius: f32, distance_squared: f32, rng: &mut impl Rng) -> Vec3 {
+    let r1: f32 = rng.gen();
+    let r2: f32 = rng.gen();
+    let z = 1.0 + r2 * ((1.0 - radius * radius / distance_squared).sqrt() - 1.0);
+
+    let phi = 2.0 * std::f32::consts::PI * r1;
+    let x = phi.cos() * (1.0 - z * z).sqrt();
+    let y = phi.sin() * (1.0 - z * z).sqrt();
+
+    vec3(x, y, z)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::SmallRng;
+    use super::*;
+
+    #[test]
+    fn samples_are_never_nan() {
+        let mut rng = SmallRng::seed_from_u64(78);
+        for _ in 0..100_000 {
+            let v = random_in_unit_sphere(&mut rng);
+            assert!(v.is_finite() && v.length_squared() > 0.0 && v.length_squared() < 1.0, "Got {}", v);
+            let v = random_in_unit_disk(&mut rng);
+            assert!(v.is_finite() && v.length_squared() > 0.0 && v.length_squared() < 1.0, "Got {}", v);
+            let v = random_unit_vector(&mut rng);
+            assert!(v.is_finite() && (v.length() - 1.0).abs() < 1e-4, "Got {}", v);
+        }
+    }
+
+    #[test]
+    fn unit_vector_of_zero_is_finite() {
+        assert_eq!(unit_vector(Vec3::ZERO), Vec3::ZERO);
+        assert_eq!(unit_vector(vec3(0.0, 3.0, 4.0)), vec3(0.0, 0.6, 0.8));
+    }
+}
@@ -0,0 +1,102 @@
+use bevy_math::vec3;
+use rand::Rng;
+use crate::types::Point3;
+
+//Mixes the coordinates of a cell with the seed into 64 random looking bits (splitmix64 finalizer)
+fn hash(x: i64, y: i64, z: i64, seed: u64) -> u64 {
+    let mut h = seed
+        ^ (x as u64).wrapping_mul(0x9E3779B97F4A7C15)
+        ^ (y as u64).wrapping_mul(0xC2B2AE3D27D4EB4F)
+        ^ (z as u64).wrapping_mul(0x165667B19E3779F9);
+    h ^= h >> 30;
+    h = h.wrapping_mul(0xBF58476D1CE4E5B9);
+    h ^= h >> 27;
+    h = h.wrapping_mul(0x94D049BB133111EB);
+    h ^ (h >> 31)
+}
+
+//Cellular noise, every unit cell of the grid has one feature point at a position picked by hashing the cell,
+//so nothing has to be stored and the pattern never repeats
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct Worley {
+    seed: u64,
+}
+
+impl Worley {
+    pub fn new(rng: &mut impl Rng) -> Self {
+        Self::with_seed(rng.gen())
+    }
+
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            seed,
+        }
+    }
+
+    fn feature_point(&self, x: i64, y: i64, z: i64) -> Point3 {
+        let h = hash(x, y, z, self.seed);
+        //21 bits per axis
+        let scale = 1.0 / (1 << 21) as f32;
+        let offset = vec3((h & 0x1FFFFF) as f32, ((h >> 21) & 0x1FFFFF) as f32, ((h >> 42) & 0x1FFFFF) as f32) * scale;
+        vec3(x as f32, y as f32, z as f32) + offset
+    }
+
+    //Distances from p to the nearest (F1) and second nearest (F2) feature points
+    pub fn distances(&self, p: Point3) -> (f32, f32) {
+        let cell = p.floor();
+        let (x, y, z) = (cell.x as i64, cell.y as i64, cell.z as i64);
+
+        let mut f1 = f32::INFINITY;
+        let mut f2 = f32::INFINITY;
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    let d = (self.feature_point(x + dx, y + dy, z + dz) - p).length_squared();
+                    if d < f1 {
+                        f2 = f1;
+                        f1 = d;
+                    } else if d < f2 {
+                        f2 = d;
+                    }
+                }
+            }
+        }
+
+        (f1.sqrt(), f2.sqrt())
+    }
+
+    pub fn f1(&self, p: Point3) -> f32 {
+        self.distances(p).0
+    }
+
+    pub fn f2(&self, p: Point3) -> f32 {
+        self.distances(p).1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::SmallRng;
+    use super::*;
+
+    fn points() -> impl Iterator<Item = Point3> {
+        (0..100).map(|i| vec3(i as f32 * 0.37, i as f32 * 0.11 - 3.0, i as f32 * -0.23))
+    }
+
+    #[test]
+    fn same_seed_gives_same_cells() {
+        let a = Worley::with_seed(42);
+        let b = Worley::with_seed(42);
+        assert!(points().all(|p| a.distances(p) == b.distances(p)));
+        //seeding through an rng works the same way
+        let c = Worley::new(&mut SmallRng::seed_from_u64(7));
+        let d = Worley::new(&mut SmallRng::seed_from_u64(7));
+        assert!(points().all(|p| c.distances(p) == d.distances(p)));
+
+        let e = Worley::with_seed(43);
+        assert!(points().any(|p| a.distances(p) != e.distances(p)));
+        assert!(points().all(|p| a.f1(p) <= a.f2(p)));
+    }
+}